@@ -3,12 +3,25 @@
 
 out vec4 color;
 layout(location=1) in  vec4 newcolors;
+layout(location=2) in  vec2 uv;
+layout(location=3) in  vec3 in_tangent;
+layout(location=4) in  vec3 in_bitangent;
 layout(location=5) in  vec3 in_normals;
+uniform layout(location = 6) bool use_texture;
+uniform layout(location = 7) bool use_normal_map;
+layout(binding = 0) uniform sampler2D diffuse_texture;
+layout(binding = 1) uniform sampler2D normal_map;
 
 
 
 void main()
 {
     vec3 lightDirection = normalize(vec3(0.8, -0.5, 0.6));
-    color = vec4(newcolors.rgb * max(0.0, dot(in_normals,-lightDirection)), newcolors.a);
+    vec3 normal = normalize(in_normals);
+    if (use_normal_map) {
+        mat3 tbn = mat3(normalize(in_tangent), normalize(in_bitangent), normal);
+        normal = normalize(tbn * (texture(normal_map, uv).rgb * 2.0 - 1.0));
+    }
+    vec4 base_color = use_texture ? newcolors * texture(diffuse_texture, uv) : newcolors;
+    color = vec4(base_color.rgb * max(0.0, dot(normal,-lightDirection)), base_color.a);
 }
\ No newline at end of file
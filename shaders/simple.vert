@@ -4,6 +4,12 @@
 in vec3 position;
 layout(location=1) in  vec4 color_in;
 layout(location=1) out  vec4 color_out;
+layout(location=2) in  vec2 uv_in;
+layout(location=2) out  vec2 uv_out;
+layout(location=3) in  vec4 tangent_in;
+layout(location=3) out  vec3 tangent_out;
+layout(location=4) in  vec3 bitangent_in;
+layout(location=4) out  vec3 bitangent_out;
 layout(location=5) in  vec3 normals_in;
 layout(location=5) out  vec3 normals_out;
 uniform layout(location = 4) mat4 transform;
@@ -14,6 +20,9 @@ void main()
 {
     gl_Position = transform * vec4(position, 1.0f);
     color_out = color_in;
+    uv_out = uv_in;
+    tangent_out = normalize(mat3(modelmat) * tangent_in.xyz);
+    bitangent_out = normalize(mat3(modelmat) * bitangent_in);
     normals_out = normalize(mat3(modelmat) * normals_in);
 
 
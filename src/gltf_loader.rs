@@ -0,0 +1,233 @@
+extern crate nalgebra_glm as glm;
+
+use crate::gpu_mesh::GpuMesh;
+use crate::mesh::{Material, Mesh, MeshError};
+use crate::scene_graph::{NodeId, SceneGraph, SceneNode};
+use crate::texture::Texture;
+use crate::vertex_layout::VertexLayout;
+
+// One mesh of a glTF file, along with the images its material paints it with
+#[allow(dead_code)]
+pub struct GltfMesh {
+    pub name       : String,
+    pub mesh       : Mesh,
+    pub texture    : Option<usize>,     // Index into GltfFile::images
+    pub normal_map : Option<usize>,     // Index into GltfFile::images, only set if the mesh has tangents
+}
+
+// The contents of a .gltf or .glb file, read but not uploaded yet
+pub struct GltfFile {
+    pub document : gltf::Document,
+    pub meshes   : Vec<GltfMesh>,            // One per glTF mesh, in file order
+    pub images   : Vec<gltf::image::Data>,   // Every image, whether in a file of its own or embedded
+}
+
+impl GltfFile {
+    // The first mesh with the given name. Unnamed meshes are called "mesh_" and their index.
+    pub fn get(&self, name: &str) -> Option<&GltfMesh> {
+        self.meshes.iter().find(|m| m.name == name)
+    }
+}
+
+// A glTF file uploaded to the GPU and added to a scene graph. The meshes and textures must be kept
+// for as long as the nodes are drawn.
+#[allow(dead_code)]
+pub struct GltfModel {
+    pub root     : NodeId,              // Parent of the top level nodes of the scene
+    pub meshes   : Vec<GpuMesh>,        // One per glTF mesh, in file order
+    pub textures : Vec<Texture>,        // One per glTF image, in file order
+}
+
+// internal helper, converts the PBR parameters into the closest thing our Material can express
+fn material_from(material: gltf::Material) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let base = pbr.base_color_factor();
+    let texture_uri = |texture: gltf::Texture| match texture.source().source() {
+        gltf::image::Source::Uri { uri, .. } => Some(uri.to_string()),
+        gltf::image::Source::View { .. } => None,
+    };
+    Material {
+        name      : material.name().unwrap_or("").to_string(),
+        ambient   : [0.0, 0.0, 0.0],
+        diffuse   : [base[0], base[1], base[2]],
+        specular  : [1.0 - pbr.roughness_factor(); 3],
+        shininess : 0.0,
+        dissolve  : base[3],
+        ambient_texture  : None,
+        diffuse_texture  : pbr.base_color_texture().and_then(|info| texture_uri(info.texture())),
+        specular_texture : None,
+        normal_texture   : material.normal_texture().and_then(|info| texture_uri(info.texture())),
+    }
+}
+
+// internal helper, reads the triangle primitives of a glTF mesh, merging them into one Mesh
+fn mesh_from(mesh: gltf::Mesh, buffers: &[gltf::buffer::Data], color: [f32; 4]) -> Mesh {
+    let mut primitives = vec![];
+    for (i, primitive) in mesh.primitives().enumerate() {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            println!("Skipping primitive {} of {}, only triangles are supported", i, mesh.name().unwrap_or("mesh"));
+            continue;
+        }
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+        let vertices: Vec<f32> = reader.read_positions()
+            .map_or(vec![], |p| p.flat_map(|v| v.to_vec()).collect());
+        let normals: Vec<f32> = reader.read_normals()
+            .map_or(vec![], |n| n.flat_map(|v| v.to_vec()).collect());
+        let uvs: Vec<f32> = reader.read_tex_coords(0)
+            .map_or(vec![], |t| t.into_f32().flat_map(|v| v.to_vec()).collect());
+        let indices: Vec<u32> = reader.read_indices()
+            .map_or_else(|| (0..(vertices.len() / 3) as u32).collect(), |i| i.into_u32().collect());
+
+        // Primitives without a material get the glTF default material, which is plain white,
+        // so those are left as None and painted our own color instead
+        let material = primitive.material().index().map(|_| material_from(primitive.material()));
+        let part_color = material.as_ref().map_or(color, Material::color);
+        let mut part = Mesh::new(vertices, normals, uvs, indices, part_color);
+        if let Some(colors) = reader.read_colors(0) {
+            part.colors = colors.into_rgba_f32().flat_map(|c| c.to_vec()).collect();
+        }
+        part.material = material;
+
+        primitives.push((format!("{}", i), part));
+    }
+    Mesh::merge(primitives, true)
+}
+
+// internal helper, the image a texture of the primitive's material is made from
+fn image_of(texture: Option<gltf::Texture>) -> Option<usize> {
+    texture.map(|t| t.source().index())
+}
+
+// Reads a .gltf or .glb file, with the images of its materials, whether they are separate files or
+// embedded in the .glb. Primitives without a material are painted `color`.
+pub fn read(path: &str, color: [f32; 4]) -> Result<GltfFile, MeshError> {
+    println!("Loading glTF model {}...", path);
+    let before = std::time::Instant::now();
+    let (document, buffers, images) = gltf::import(path)
+        .map_err(|error| MeshError::Gltf { path: path.to_string(), error })?;
+
+    let meshes = document.meshes()
+        .map(|m| {
+            let name = m.name().map_or_else(|| format!("mesh_{}", m.index()), String::from);
+            // A mesh is drawn with one texture, the one of its first textured primitive
+            let texture = m.primitives()
+                .find_map(|p| image_of(p.material().pbr_metallic_roughness().base_color_texture().map(|i| i.texture())));
+            let normal_map = m.primitives()
+                .find_map(|p| image_of(p.material().normal_texture().map(|i| i.texture())));
+            let mut mesh = mesh_from(m, &buffers, color);
+            let normal_map = mesh.with_normal_map(normal_map);
+            GltfMesh { name, mesh, texture, normal_map }
+        })
+        .collect();
+
+    let after = std::time::Instant::now();
+    println!("Done in {:.3}ms!", after.duration_since(before).as_micros() as f32 / 1e3);
+    Ok(GltfFile { document, meshes, images })
+}
+
+// internal helper, converts any of the pixel formats of glTF images to 8 bit RGBA
+fn rgba8(image: &gltf::image::Data) -> Vec<u8> {
+    use gltf::image::Format;
+    let (channels, bytes) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 | Format::B8G8R8 => (3, 1),
+        Format::R8G8B8A8 | Format::B8G8R8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+    };
+    let bgr = matches!(image.format, Format::B8G8R8 | Format::B8G8R8A8);
+    image.pixels.chunks_exact(channels * bytes)
+        .flat_map(|pixel| {
+            // 16 bit channels keep their most significant byte
+            let c = |i: usize| if bytes == 2 { (u16::from_ne_bytes([pixel[i * 2], pixel[i * 2 + 1]]) >> 8) as u8 } else { pixel[i] };
+            let rgba = match channels {
+                1 => [c(0), c(0), c(0), 255],
+                2 => [c(0), c(0), c(0), c(1)],   // Luminance and alpha
+                3 => [c(0), c(1), c(2), 255],
+                _ => [c(0), c(1), c(2), c(3)],
+            };
+            if bgr { [rgba[2], rgba[1], rgba[0], rgba[3]] } else { rgba }
+        })
+        .collect()
+}
+
+// Uploads a glTF image into a texture. glTF puts the first row of an image at the top, where our
+// texture coordinates expect it, so unlike Texture::load it isn't flipped.
+pub unsafe fn upload_image(image: &gltf::image::Data) -> Texture {
+    Texture::from_rgba(image.width, image.height, &rgba8(image))
+}
+
+// Loads a .gltf or .glb file into the graph, mirroring its node hierarchy, with each node's
+// translation, rotation and scale, below a new root node named after the file. Nodes sharing a
+// mesh share its GpuMesh, and textures are shared the same way.
+#[allow(dead_code)]
+pub unsafe fn load(path: &str, color: [f32; 4], layout: &VertexLayout, graph: &mut SceneGraph) -> Result<GltfModel, MeshError> {
+    let file = read(path, color)?;
+    let meshes: Vec<GpuMesh> = file.meshes.iter().map(|m| GpuMesh::upload(&m.mesh, layout)).collect();
+    let textures: Vec<Texture> = file.images.iter().map(|image| upload_image(image)).collect();
+
+    let scene = file.document.default_scene()
+        .or_else(|| file.document.scenes().next())
+        .ok_or_else(|| MeshError::NoMeshes { path: path.to_string() })?;
+
+    fn add_node(gltf_node: gltf::Node, parent: NodeId, graph: &mut SceneGraph, file: &GltfFile, meshes: &[GpuMesh], textures: &[Texture]) -> usize {
+        let mut node = SceneNode::default();
+        if let Some(mesh) = gltf_node.mesh() {
+            let (gpu, m) = (&meshes[mesh.index()], &file.meshes[mesh.index()]);
+            node.vao_id        = gpu.vao_id();
+            node.index_count   = gpu.index_count();
+            node.primitive     = gpu.primitive();
+            node.bounds        = Some(m.mesh.bounds);
+            node.texture_id    = m.texture.map_or(0, |i| textures[i].texture_id);
+            node.normal_map_id = m.normal_map.map_or(0, |i| textures[i].texture_id);
+        }
+        let (translation, rotation, scale) = gltf_node.transform().decomposed();
+        node.position    = glm::make_vec3(&translation);
+        node.orientation = glm::quat(rotation[0], rotation[1], rotation[2], rotation[3]);
+        node.scale       = glm::make_vec3(&scale);
+        node.name        = Some(gltf_node.name().map_or_else(|| format!("node_{}", gltf_node.index()), String::from));
+
+        let id = graph.add(node);
+        graph.add_child(parent, id);
+        1 + gltf_node.children()
+            .map(|child| add_node(child, id, graph, file, meshes, textures))
+            .sum::<usize>()
+    }
+
+    let name = std::path::Path::new(path).file_stem().map(|s| s.to_string_lossy().into_owned());
+    let root = graph.add(SceneNode { name, ..SceneNode::default() });
+    let count: usize = scene.nodes()
+        .map(|gltf_node| add_node(gltf_node, root, graph, &file, &meshes, &textures))
+        .sum();
+    println!("Added {} meshes and {} nodes to the scene.", meshes.len(), count);
+
+    Ok(GltfModel { root, meshes, textures })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gltf::image::{Data, Format};
+
+    fn image(format: Format, pixels: Vec<u8>) -> Data {
+        Data { pixels, format, width: 1, height: 1 }
+    }
+
+    #[test]
+    fn luminance_alpha_becomes_gray() {
+        assert_eq!(rgba8(&image(Format::R8G8, vec![100, 50])), vec![100, 100, 100, 50]);
+        let pixel = [0x1234u16.to_ne_bytes(), 0xabcdu16.to_ne_bytes()].concat();
+        assert_eq!(rgba8(&image(Format::R16G16, pixel)), vec![0x12, 0x12, 0x12, 0xab]);
+    }
+
+    #[test]
+    fn other_formats() {
+        assert_eq!(rgba8(&image(Format::R8, vec![7])), vec![7, 7, 7, 255]);
+        assert_eq!(rgba8(&image(Format::R8G8B8, vec![1, 2, 3])), vec![1, 2, 3, 255]);
+        assert_eq!(rgba8(&image(Format::B8G8R8A8, vec![1, 2, 3, 4])), vec![3, 2, 1, 4]);
+    }
+}
@@ -0,0 +1,96 @@
+use std::ptr;
+
+use crate::mesh::Mesh;
+use crate::vertex_layout::VertexLayout;
+use crate::{byte_size_of_array, pointer_to_array};
+
+// A mesh uploaded to the GPU. Owns its VAO and buffers, and deletes them when dropped, so it must
+// be dropped on the thread with the OpenGL context.
+pub struct GpuMesh {
+    vao_id      : u32,
+    vbo_id      : u32,
+    ibo_id      : u32,
+    index_count : i32,
+    primitive   : gl::types::GLenum,
+    layout      : VertexLayout,     // The layout actually in use, without attributes the mesh lacks
+}
+
+impl GpuMesh {
+    // Packs the mesh into one interleaved vertex buffer laid out as `layout`, plus its index
+    // buffer. Attributes the mesh has no data for are left out.
+    pub unsafe fn upload(mesh: &Mesh, layout: &VertexLayout) -> GpuMesh {
+        let mut gpu_mesh = GpuMesh {
+            vao_id: 0,
+            vbo_id: 0,
+            ibo_id: 0,
+            index_count: 0,
+            primitive: gl::TRIANGLES,
+            layout: VertexLayout::new(),
+        };
+        gl::GenVertexArrays(1, &mut gpu_mesh.vao_id);
+        gl::GenBuffers(1, &mut gpu_mesh.vbo_id);
+        gl::GenBuffers(1, &mut gpu_mesh.ibo_id);
+        gpu_mesh.reupload(mesh, layout);
+        gpu_mesh
+    }
+
+    // Replaces the contents of the buffers, e.g. after the mesh was edited. The VAO keeps its id,
+    // so nodes referring to it see the new data.
+    pub unsafe fn reupload(&mut self, mesh: &Mesh, layout: &VertexLayout) {
+        gl::BindVertexArray(self.vao_id);
+        self.layout.disable();
+        self.layout = layout.for_mesh(mesh);
+
+        let vertex_data = self.layout.pack(mesh);
+        gl::BindBuffer(gl::ARRAY_BUFFER, self.vbo_id);
+        if vertex_data.is_empty() {
+            gl::BufferData(gl::ARRAY_BUFFER, 0, ptr::null(), gl::STATIC_DRAW);
+        } else {
+            gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(&vertex_data), pointer_to_array(&vertex_data), gl::STATIC_DRAW);
+        }
+        self.layout.enable();
+
+        gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, self.ibo_id);
+        if mesh.indices.is_empty() {
+            gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, 0, ptr::null(), gl::STATIC_DRAW);
+        } else {
+            gl::BufferData(gl::ELEMENT_ARRAY_BUFFER, byte_size_of_array(&mesh.indices), pointer_to_array(&mesh.indices), gl::STATIC_DRAW);
+        }
+        self.index_count = mesh.indices.len() as i32;
+    }
+
+    pub fn vao_id(&self) -> u32 {
+        self.vao_id
+    }
+
+    pub fn index_count(&self) -> i32 {
+        self.index_count
+    }
+
+    pub fn primitive(&self) -> gl::types::GLenum {
+        self.primitive
+    }
+
+    // Meshes are triangle lists unless told otherwise, e.g. gl::LINES for a wireframe made by hand.
+    // Nodes copy the primitive when they're made, so set it before making them.
+    #[allow(dead_code)]
+    pub fn set_primitive(&mut self, primitive: gl::types::GLenum) {
+        self.primitive = primitive;
+    }
+
+    #[allow(dead_code)]
+    pub unsafe fn draw(&self) {
+        gl::BindVertexArray(self.vao_id);
+        gl::DrawElements(self.primitive, self.index_count, gl::UNSIGNED_INT, ptr::null());
+    }
+}
+
+impl Drop for GpuMesh {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.vbo_id);
+            gl::DeleteBuffers(1, &self.ibo_id);
+            gl::DeleteVertexArrays(1, &self.vao_id);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+use std::mem;
+use std::ptr;
+
+use crate::scene_graph::SceneNode;
+use crate::shader::Shader;
+use crate::{byte_size_of_array, pointer_to_array};
+
+// Draws nodes that share a mesh with one glDrawElementsInstanced per mesh, instead of one draw
+// call per node. Nodes are collected with add, typically from SceneGraph::draw, and drawn by
+// flush. Nodes whose mesh isn't shared by enough others are drawn one by one as usual.
+//
+// The instanced draws use a shader reading the model matrix from a per instance attribute, like
+// instanced.vert, and the others a shader reading it from uniforms, like simple.vert. Both should
+// use the same fragment shader, so a node looks the same whichever way it's drawn.
+
+// Attribute location of the model matrix in instanced.vert. A mat4 takes up four locations, one
+// per column.
+const MODEL_MATRIX_LOCATION: u32 = 6;
+
+// What nodes need to have in common to be drawn together
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct BatchKey {
+    vao_id        : u32,
+    index_count   : i32,
+    primitive     : gl::types::GLenum,
+    texture_id    : u32,
+    normal_map_id : u32,
+}
+
+pub struct InstanceBatcher {
+    buffer_id     : u32,
+    keys          : HashMap<BatchKey, usize>,
+    groups        : Vec<(BatchKey, Vec<glm::Mat4>)>, // In the order they were first seen
+    min_instances : usize,
+    draw_calls    : usize,
+}
+
+impl InstanceBatcher {
+    // Meshes used by fewer than `min_instances` nodes are drawn one node at a time, as setting up
+    // an instanced draw isn't worth it for one or two
+    pub unsafe fn new(min_instances: usize) -> InstanceBatcher {
+        let mut buffer_id = 0;
+        gl::GenBuffers(1, &mut buffer_id);
+        InstanceBatcher {
+            buffer_id,
+            keys: HashMap::new(),
+            groups: vec![],
+            min_instances: min_instances.max(1),
+            draw_calls: 0,
+        }
+    }
+
+    // Queues the node to be drawn with the given model matrix, at the level of detail fitting its
+    // size on screen
+    pub fn add(&mut self, node: &SceneNode, model_matrix: &glm::Mat4, view_projection: &glm::Mat4) {
+        let (vao_id, index_count) = select_lod(node, model_matrix, view_projection);
+        let key = BatchKey {
+            vao_id,
+            index_count,
+            primitive: node.primitive,
+            texture_id: node.texture_id,
+            normal_map_id: node.normal_map_id,
+        };
+        let groups = &mut self.groups;
+        let group = *self.keys.entry(key).or_insert_with(|| {
+            groups.push((key, vec![]));
+            groups.len() - 1
+        });
+        self.groups[group].1.push(*model_matrix);
+    }
+
+    // Draws everything queued since the last flush, leaving `shader` active
+    pub unsafe fn flush(&mut self, shader: &Shader, instanced_shader: &Shader, view_projection: &glm::Mat4) {
+        let min_instances = self.min_instances;
+        self.draw_calls = 0;
+
+        instanced_shader.activate();
+        gl::UniformMatrix4fv(4, 1, gl::FALSE, view_projection.as_ptr());
+        for (key, models) in self.groups.iter().filter(|(_, models)| models.len() >= min_instances) {
+            bind_textures(key);
+            gl::BindVertexArray(key.vao_id);
+            gl::BindBuffer(gl::ARRAY_BUFFER, self.buffer_id);
+            gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(models), pointer_to_array(models), gl::STREAM_DRAW);
+            let column_size = 4 * mem::size_of::<f32>();
+            for column in 0..4 {
+                let location = MODEL_MATRIX_LOCATION + column as u32;
+                gl::VertexAttribPointer(location, 4, gl::FLOAT, gl::FALSE, (4 * column_size) as i32,
+                    ptr::null::<u8>().add(column * column_size) as *const _);
+                gl::EnableVertexAttribArray(location);
+                gl::VertexAttribDivisor(location, 1);
+            }
+            gl::DrawElementsInstanced(key.primitive, key.index_count, gl::UNSIGNED_INT, ptr::null(), models.len() as i32);
+            // The VAO belongs to the mesh, leave it the way it was
+            for column in 0..4 {
+                gl::DisableVertexAttribArray(MODEL_MATRIX_LOCATION + column);
+            }
+            self.draw_calls += 1;
+        }
+
+        shader.activate();
+        for (key, models) in self.groups.iter().filter(|(_, models)| models.len() < min_instances) {
+            bind_textures(key);
+            gl::BindVertexArray(key.vao_id);
+            for model in models {
+                let mvp = view_projection * model;
+                gl::UniformMatrix4fv(4, 1, gl::FALSE, mvp.as_ptr());
+                gl::UniformMatrix4fv(2, 1, gl::FALSE, model.as_ptr());
+                gl::DrawElements(key.primitive, key.index_count, gl::UNSIGNED_INT, ptr::null());
+                self.draw_calls += 1;
+            }
+        }
+
+        self.keys.clear();
+        self.groups.clear();
+    }
+
+    // How many draw calls the last flush made, for profiling
+    #[allow(dead_code)]
+    pub fn draw_calls(&self) -> usize {
+        self.draw_calls
+    }
+}
+
+impl Drop for InstanceBatcher {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteBuffers(1, &self.buffer_id);
+        }
+    }
+}
+
+// internal helper, the VAO and index count to draw the node with, picking the coarsest LOD whose
+// threshold it's below
+fn select_lod(node: &SceneNode, world: &glm::Mat4, view_projection: &glm::Mat4) -> (u32, i32) {
+    match node.screen_size(world, view_projection) {
+        Some(size) => node.lods.iter()
+            .rev()
+            .find(|lod| size < lod.screen_size)
+            .map_or((node.vao_id, node.index_count), |lod| (lod.vao_id, lod.index_count)),
+        None => (node.vao_id, node.index_count),
+    }
+}
+
+// internal helper, binds the textures of a group and tells the fragment shader which it has
+unsafe fn bind_textures(key: &BatchKey) {
+    if key.texture_id != 0 {
+        gl::ActiveTexture(gl::TEXTURE0);
+        gl::BindTexture(gl::TEXTURE_2D, key.texture_id);
+        gl::Uniform1i(6, 1);
+    } else {
+        gl::Uniform1i(6, 0);
+    }
+    if key.normal_map_id != 0 {
+        gl::ActiveTexture(gl::TEXTURE1);
+        gl::BindTexture(gl::TEXTURE_2D, key.normal_map_id);
+        gl::Uniform1i(7, 1);
+    } else {
+        gl::Uniform1i(7, 0);
+    }
+}
@@ -6,9 +6,20 @@ use std::sync::{Mutex, Arc, RwLock};
 mod shader;
 mod util;
 mod mesh;
+mod mesh_cache;
+mod optimize;
+mod gltf_loader;
 mod scene_graph;
-use scene_graph::SceneNode;
+use scene_graph::NodeId;
 mod toolbox;
+mod texture;
+mod primitives;
+mod simplify;
+mod vertex_layout;
+use vertex_layout::VertexLayout;
+mod gpu_mesh;
+mod instancing;
+mod scene_file;
 
 use glutin::event::{Event, WindowEvent, DeviceEvent, KeyboardInput, ElementState::{Pressed, Released}, VirtualKeyCode::{self, *}};
 use glutin::event_loop::ControlFlow;
@@ -40,97 +51,6 @@ fn offset<T>(n: u32) -> *const c_void {
 
 
 
-// == // Modify and complete the function below for the first task
-unsafe fn setup_vao(vek: &Vec<f32>, ind: &Vec<u32>, col: &Vec<f32>, n_vec: &Vec<f32>) -> u32 {
-
-    /* Sets up a Vertex Array Object containing triangles
-       Returns the integer ID of the created VAO
-
-       #Args
-       * 'vek'   - Vector of 3D vertex coordinates
-       * 'ind'   - Vector of indices
-       * 'col'   - Vector of rgba values
-       * 'n_vec' - Vector of normal vectors
-    */ 
-
-    let mut vao: gl::types::GLuint=0;
-    let mut vbo: gl::types::GLuint=0;
-    let mut ibuffer: gl::types::GLuint=0;
-    let mut color_vbo: gl::types::GLuint=0;
-    let mut n_vec_vbo: gl::types::GLuint=0;
-    //let mut uni: gl::types::GLuint=0; //used for task 3
-    gl::GenVertexArrays(1, &mut vao);
-    gl::BindVertexArray(vao);
-    gl::GenBuffers(1,&mut vbo);
-    gl::BindBuffer(gl::ARRAY_BUFFER,vbo);
-    gl::BufferData(gl::ARRAY_BUFFER,byte_size_of_array(vek),pointer_to_array(vek),gl::STATIC_DRAW);
-    gl::VertexAttribPointer(0,3,gl::FLOAT,gl::FALSE,0,ptr::null());
-    gl::EnableVertexAttribArray(0);
-    gl::GenBuffers(1,&mut ibuffer);
-    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER,ibuffer);
-    gl::BufferData(gl::ELEMENT_ARRAY_BUFFER,byte_size_of_array(ind),pointer_to_array(ind),gl::STATIC_DRAW);
-
-    //color
-    gl::GenBuffers(1, &mut color_vbo);
-    gl::BindBuffer(gl::ARRAY_BUFFER, color_vbo);
-    //gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(col), pointer_to_array(col), gl::STATIC_DRAW);
-    gl::BufferData(gl::ARRAY_BUFFER, (mem::size_of::<f32>()*col.len()) as isize, pointer_to_array(col), gl::STATIC_DRAW);
-    gl::VertexAttribPointer(1,4,gl::FLOAT,gl::FALSE,16,ptr::null());
-    gl::EnableVertexAttribArray(1);
-
-    //normal vectors
-    gl::GenBuffers(1, &mut n_vec_vbo);
-    gl::BindBuffer(gl::ARRAY_BUFFER, n_vec_vbo);
-    gl::BufferData(gl::ARRAY_BUFFER, (mem::size_of::<f32>()*n_vec.len()) as isize, pointer_to_array(n_vec), gl::STATIC_DRAW);
-    gl::VertexAttribPointer(5,3,gl::FLOAT,gl::FALSE,0,ptr::null());
-    gl::EnableVertexAttribArray(5);
-
-    return vao;
-}
-
-//function to traverse and draw the scenegraph
-unsafe fn draw_scene(node: &scene_graph::SceneNode,
-    view_projection_matrix: &glm::Mat4) {
-    // Check if node is drawable, set uniforms, draw
-    //avoid drawing the rootnode
-    if node.vao_id != 0 {
-        let mvp=view_projection_matrix*node.current_transformation_matrix;
-        gl::BindVertexArray(node.vao_id);
-        gl::UniformMatrix4fv(4, 1, gl::FALSE, mvp.as_ptr());
-        gl::UniformMatrix4fv(2, 1, gl::FALSE, node.current_transformation_matrix.as_ptr());
-        gl::DrawElements(gl::TRIANGLES,node.index_count,gl::UNSIGNED_INT,ptr::null());
-    }
-    // Recurse
-    for &child in &node.children {
-        draw_scene(&*child, &view_projection_matrix);
-    }
-}
-
-//function to traverse and update the scenegraph
-unsafe fn update_node_transformations(node: &mut scene_graph::SceneNode,
-    transformation_so_far: &glm::Mat4) {
-    // Construct the correct transformation matrix
-    let nref = -node.reference_point;
-    let mut trans: glm::Mat4 = glm::identity();
-    trans = glm::translation(&nref)*trans;
-    trans = glm::scaling(&node.scale)*trans;
-    trans = glm::rotation(node.rotation[0], &glm::vec3(1.0, 0.0, 0.0))*trans;
-    trans = glm::rotation(node.rotation[1], &glm::vec3(0.0, 1.0, 0.0))*trans;
-    trans = glm::rotation(node.rotation[2], &glm::vec3(0.0, 0.0, 1.0))*trans;
-    trans = glm::translation(&node.reference_point)*trans;
-    trans = glm::translation(&node.position)*trans;
-    trans = transformation_so_far*trans;
-
-    // Update the node's transformation matrix
-    node.current_transformation_matrix=trans;
-    // Recurse
-    for &child in &node.children {
-    update_node_transformations(&mut *child,
-    &node.current_transformation_matrix);
-    }
-    }
-    
-
 fn main() {
     // Set up the necessary objects to deal with windows and event handling
     let el = glutin::event_loop::EventLoop::new();
@@ -187,7 +107,7 @@ fn main() {
         // The snippet is not enough to do the assignment, and will need to be modified (outside of
         // just using the correct path), but it only needs to be called once
         //
-        unsafe {
+        let (shader, instanced_shader) = unsafe {
                 //attach and activate the shaders
                 let shader = shader::ShaderBuilder::new()
                 .attach_file("./shaders/simple.frag")
@@ -195,83 +115,44 @@ fn main() {
                 .link();
                 shader.activate();
 
+                //the same, but with the model matrices coming from an instance buffer
+                let instanced_shader = shader::ShaderBuilder::new()
+                .attach_file("./shaders/simple.frag")
+                .attach_file("./shaders/instanced.vert")
+                .link();
 
-        }
-
-        //load the terrain model
-        let mesh = mesh::Terrain::load("./resources/lunarsurface.obj");
-
-        //set up new vao for the terrain model
-        let terrain_vao= unsafe {
-            let vek:     Vec<f32> = mesh.vertices;
-            let indices: Vec<u32> = mesh.indices;
-            let color:   Vec<f32> = mesh.colors;
-            let normals: Vec<f32> = mesh.normals;
-            setup_vao(&vek, &indices, &color, &normals)
+                (shader, instanced_shader)
         };
 
+        //draws the parts shared by several helicopters in one go
+        let mut batcher = unsafe { instancing::InstanceBatcher::new(3) };
 
-        //load the helicopter model
-        let heli= mesh::Helicopter::load("./resources/helicopter.obj");
-        
-        //setup the vaos for the different parts of the heli
-        let body_vao = unsafe {
-            let vek:     Vec<f32> = heli.body.vertices;
-            let indices: Vec<u32> = heli.body.indices;
-            let color:   Vec<f32> = heli.body.colors;
-            let normals: Vec<f32> = heli.body.normals;
-            setup_vao(&vek, &indices, &color, &normals)
-        };
-        let door_vao = unsafe {
-            let vek:     Vec<f32> = heli.door.vertices;
-            let indices: Vec<u32> = heli.door.indices;
-            let color:   Vec<f32> = heli.door.colors;
-            let normals: Vec<f32> = heli.door.normals;
-            setup_vao(&vek, &indices, &color, &normals)
-        };
-        let main_rotor_vao = unsafe {
-            let vek:     Vec<f32> = heli.main_rotor.vertices;
-            let indices: Vec<u32> = heli.main_rotor.indices;
-            let color:   Vec<f32> = heli.main_rotor.colors;
-            let normals: Vec<f32> = heli.main_rotor.normals;
-            setup_vao(&vek, &indices, &color, &normals)
-        };
-        let tail_rotor_vao = unsafe {
-            let vek:     Vec<f32> = heli.tail_rotor.vertices;
-            let indices: Vec<u32> = heli.tail_rotor.indices;
-            let color:   Vec<f32> = heli.tail_rotor.colors;
-            let normals: Vec<f32> = heli.tail_rotor.normals;
-            setup_vao(&vek, &indices, &color, &normals)
-        };
-
-        //setup the scene graph
+        //every mesh is packed into one interleaved buffer laid out the way the shaders expect
+        let layout = VertexLayout::standard();
 
-        //generate nodes for all objects
-        let mut terrain = SceneNode::from_vao(terrain_vao, mesh.index_count);
-        let mut body = SceneNode::from_vao(body_vao, heli.body.index_count);
-        let mut door = SceneNode::from_vao(door_vao, heli.door.index_count);
-        let mut main_rotor = SceneNode::from_vao(main_rotor_vao, heli.main_rotor.index_count);
-        let mut tail_rotor = SceneNode::from_vao(tail_rotor_vao, heli.tail_rotor.index_count);
+        //load the terrain and the helicopters flying over it, see scene_file for how it's described
+        //a missing or broken asset leaves a placeholder to look at instead of a crashed program
+        let mut scene = unsafe { scene_file::Scene::load("./scenes/lunar_helicopters.ron", &layout) }
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to load the scene: {}", e);
+                unsafe { scene_file::Scene::placeholder(&layout) }
+            });
+        let terrain = scene.graph.find("terrain");
 
-        //set the reference points for the nodes
-        tail_rotor.reference_point = glm::vec3(0.35, 2.3, 10.4);
-        main_rotor.reference_point = glm::vec3(0.0, 2.3, 0.0);
-        body.reference_point       = glm::vec3(0.0, 0.0, 0.0);
+        //each helicopter has its own door, so it can be dropped, along with where the door sits on
+        //the helicopter so it can be put back
+        let helicopters: Vec<(NodeId, NodeId, (glm::Vec3, glm::Quat, glm::Vec3))> = scene.graph
+            .find_all(|node| node.name.as_deref() == Some("helicopter"))
+            .filter_map(|heli| scene.graph.children(heli).iter()
+                .find(|&&child| scene.graph[child].name.as_deref() == Some("door"))
+                .map(|&door| {
+                    let d = &scene.graph[door];
+                    (heli, door, (d.position, d.orientation, d.scale))
+                }))
+            .collect();
 
-
-        //make a vector of rootnodes
-        let mut root_nodes: Vec<scene_graph::Node> = vec![];
-        for i in 1..6 {
-            root_nodes.push(SceneNode::new());
-            root_nodes[i-1].add_child(&terrain);
-        }
-
-
-        //organize the graph
-        terrain.add_child(&body);
-        body.add_child(&door);
-        body.add_child(&main_rotor);
-        body.add_child(&tail_rotor);
+        //the scene is saved when P is pressed, not for every frame it's held down
+        let mut save_pressed = false;
 
 
 
@@ -332,17 +213,49 @@ fn main() {
                         },
 
                         VirtualKeyCode::Z => {
-                            door.position.z= -elapsed/5.0;
+                            for &(_, door, _) in &helicopters {
+                                scene.graph[door].position.z= -elapsed/5.0;
+                            }
                         }
 
                         VirtualKeyCode::X => {
-                            door.position.z=0.0;
+                            //put the doors back where they were, a dropped door's pose is in world space
+                            for &(instance, door, (position, orientation, scale)) in &helicopters {
+                                if scene.graph.parent(door) != Some(instance) {
+                                    scene.graph.reparent(door, instance, false);
+                                }
+                                let door = &mut scene.graph[door];
+                                door.position = position;
+                                door.orientation = orientation;
+                                door.scale = scale;
+                            }
+                        }
+
+                        //drop the doors, leaving them on the terrain where they fell off
+                        VirtualKeyCode::C => {
+                            if let Some(terrain) = terrain {
+                                for &(_, door, _) in &helicopters {
+                                    if scene.graph.parent(door) != Some(terrain) {
+                                        scene.graph.reparent(door, terrain, true);
+                                    }
+                                }
+                            }
                         }
 
                         _ => { }
                     }
                 }
             }
+            //write the scene as it is now, e.g. with the doors dropped, to a file that can be loaded instead
+            if let Ok(keys) = pressed_keys.lock() {
+                if keys.contains(&VirtualKeyCode::P) && !save_pressed {
+                    match scene.save("./scenes/saved.ron") {
+                        Ok(()) => println!("Saved the scene to ./scenes/saved.ron"),
+                        Err(e) => println!("Failed to save the scene: {}", e),
+                    }
+                }
+                save_pressed = keys.contains(&VirtualKeyCode::P);
+            }
             // Handle mouse movement. delta contains the x and y movement of the mouse since last frame in pixels
             if let Ok(mut delta) = mouse_delta.lock() {
 
@@ -362,30 +275,13 @@ fn main() {
                 //apply transformations
                 matrise=persp*trans*z_translation;
 
-                //transformations so far, to be updated by the traversal-function
-                let sofar: glm::Mat4 = glm::identity();
-
                 
-                //setup the animations
-                tail_rotor.rotation.x = elapsed*10.0;
-                main_rotor.rotation.y = elapsed*100.0;
-
-            
+                //move the helicopters and spin their rotors
+                scene.graph.animate(elapsed);
 
-                let mut i: f32 = 0.0;
-                for root in &mut root_nodes {
-                    //get the heading
-                    let heading = toolbox::simple_heading_animation(elapsed+i/2.0 as f32);
-                    i+=1.5;
-                    //setup for animation
-                    body.position.x = heading.x;
-                    body.position.z = heading.z;
-                    body.rotation.z = heading.roll;
-                    body.rotation.y = heading.yaw;
-                    body.rotation.x = heading.pitch;
-                    update_node_transformations(root, &sofar);
-                    draw_scene(root, &matrise);
-                }
+                scene.graph.update_transformations();
+                scene.graph.draw(|node, model| batcher.add(node, model, &matrise));
+                batcher.flush(&shader, &instanced_shader, &matrise);
 
             }
 
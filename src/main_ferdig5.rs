@@ -6,9 +6,15 @@ use std::sync::{Mutex, Arc, RwLock};
 mod shader;
 mod util;
 mod mesh;
+mod mesh_cache;
+mod optimize;
 mod scene_graph;
-use scene_graph::SceneNode;
+use scene_graph::{EulerOrder, SceneGraph, SceneNode};
 mod toolbox;
+mod vertex_layout;
+use vertex_layout::VertexLayout;
+mod gpu_mesh;
+use gpu_mesh::GpuMesh;
 
 use glutin::event::{Event, WindowEvent, DeviceEvent, KeyboardInput, ElementState::{Pressed, Released}, VirtualKeyCode::{self, *}};
 use glutin::event_loop::ControlFlow;
@@ -40,95 +46,15 @@ fn offset<T>(n: u32) -> *const c_void {
 
 
 
-// == // Modify and complete the function below for the first task
-unsafe fn setup_vao(vek: &Vec<f32>, ind: &Vec<u32>, col: &Vec<f32>, n_vec: &Vec<f32>) -> u32 {
-
-    /* Sets up a Vertex Array Object containing triangles
-       Returns the integer ID of the created VAO
-
-       #Args
-       * 'vek'   - Vector of 3D vertex coordinates
-       * 'ind'   - Vector of indices
-       * 'col'   - Vector of rgba values
-       * 'n_vec' - Vector of normal vectors
-    */ 
-
-    let mut vao: gl::types::GLuint=0;
-    let mut vbo: gl::types::GLuint=0;
-    let mut ibuffer: gl::types::GLuint=0;
-    let mut color_vbo: gl::types::GLuint=0;
-    let mut n_vec_vbo: gl::types::GLuint=0;
-    //let mut uni: gl::types::GLuint=0; //used for task 3
-    gl::GenVertexArrays(1, &mut vao);
-    gl::BindVertexArray(vao);
-    gl::GenBuffers(1,&mut vbo);
-    gl::BindBuffer(gl::ARRAY_BUFFER,vbo);
-    gl::BufferData(gl::ARRAY_BUFFER,byte_size_of_array(vek),pointer_to_array(vek),gl::STATIC_DRAW);
-    gl::VertexAttribPointer(0,3,gl::FLOAT,gl::FALSE,0,ptr::null());
-    gl::EnableVertexAttribArray(0);
-    gl::GenBuffers(1,&mut ibuffer);
-    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER,ibuffer);
-    gl::BufferData(gl::ELEMENT_ARRAY_BUFFER,byte_size_of_array(ind),pointer_to_array(ind),gl::STATIC_DRAW);
-
-    //color
-    gl::GenBuffers(1, &mut color_vbo);
-    gl::BindBuffer(gl::ARRAY_BUFFER, color_vbo);
-    //gl::BufferData(gl::ARRAY_BUFFER, byte_size_of_array(col), pointer_to_array(col), gl::STATIC_DRAW);
-    gl::BufferData(gl::ARRAY_BUFFER, (mem::size_of::<f32>()*col.len()) as isize, pointer_to_array(col), gl::STATIC_DRAW);
-    gl::VertexAttribPointer(1,4,gl::FLOAT,gl::FALSE,16,ptr::null());
-    gl::EnableVertexAttribArray(1);
-
-    //normal vectors
-    gl::GenBuffers(1, &mut n_vec_vbo);
-    gl::BindBuffer(gl::ARRAY_BUFFER, n_vec_vbo);
-    gl::BufferData(gl::ARRAY_BUFFER, (mem::size_of::<f32>()*n_vec.len()) as isize, pointer_to_array(n_vec), gl::STATIC_DRAW);
-    gl::VertexAttribPointer(5,3,gl::FLOAT,gl::FALSE,0,ptr::null());
-    gl::EnableVertexAttribArray(5);
-
-    return vao;
-}
-
-//function to traverse and draw the scenegraph
-unsafe fn draw_scene(node: &scene_graph::SceneNode,
+//function to draw one node of the scenegraph
+unsafe fn draw_node(node: &scene_graph::SceneNode, model_matrix: &glm::Mat4,
     view_projection_matrix: &glm::Mat4) {
-    // Check if node is drawable, set uniforms, draw
-    //avoid drawing the rootnode
-    if node.vao_id != 0 {
-        let mvp=view_projection_matrix*node.current_transformation_matrix;
-        gl::BindVertexArray(node.vao_id);
-        gl::UniformMatrix4fv(4, 1, gl::FALSE, mvp.as_ptr());
-        gl::UniformMatrix4fv(2, 1, gl::FALSE, node.current_transformation_matrix.as_ptr());
-        gl::DrawElements(gl::TRIANGLES,node.index_count,gl::UNSIGNED_INT,ptr::null());
-    }
-    // Recurse
-    for &child in &node.children {
-        draw_scene(&*child, &view_projection_matrix);
-    }
+    let mvp=view_projection_matrix*model_matrix;
+    gl::BindVertexArray(node.vao_id);
+    gl::UniformMatrix4fv(4, 1, gl::FALSE, mvp.as_ptr());
+    gl::UniformMatrix4fv(2, 1, gl::FALSE, model_matrix.as_ptr());
+    gl::DrawElements(node.primitive,node.index_count,gl::UNSIGNED_INT,ptr::null());
 }
-
-//function to traverse and update the scenegraph
-unsafe fn update_node_transformations(node: &mut scene_graph::SceneNode,
-    transformation_so_far: &glm::Mat4) {
-    // Construct the correct transformation matrix
-    let nref = -node.reference_point;
-    let mut trans: glm::Mat4 = glm::identity();
-    trans = glm::translation(&nref)*trans;
-    trans = glm::scaling(&node.scale)*trans;
-    trans = glm::rotation(node.rotation[0], &glm::vec3(1.0, 0.0, 0.0))*trans;
-    trans = glm::rotation(node.rotation[1], &glm::vec3(0.0, 1.0, 0.0))*trans;
-    trans = glm::rotation(node.rotation[2], &glm::vec3(0.0, 0.0, 1.0))*trans;
-    trans = glm::translation(&node.reference_point)*trans;
-    trans = glm::translation(&node.position)*trans;
-    trans = transformation_so_far*trans;
-
-    // Update the node's transformation matrix
-    node.current_transformation_matrix=trans;
-    // Recurse
-    for &child in &node.children {
-    update_node_transformations(&mut *child,
-    &node.current_transformation_matrix);
-    }
-    }
     
 
 fn main() {
@@ -198,73 +124,48 @@ fn main() {
 
         }
 
-        //load the terrain model
-        let mesh = mesh::Terrain::load("./resources/lunarsurface.obj");
-
-        //set up new vao for the terrain model
-        let terrain_vao= unsafe {
-            let vek:     Vec<f32> = mesh.vertices;
-            let indices: Vec<u32> = mesh.indices;
-            let color:   Vec<f32> = mesh.colors;
-            let normals: Vec<f32> = mesh.normals;
-            setup_vao(&vek, &indices, &color, &normals)
-        };
-
-
-        //load the helicopter model
-        let heli= mesh::Helicopter::load("./resources/helicopter.obj");
-        
-        //setup the vaos for the different parts of the heli
-        let body_vao = unsafe {
-            let vek:     Vec<f32> = heli.body.vertices;
-            let indices: Vec<u32> = heli.body.indices;
-            let color:   Vec<f32> = heli.body.colors;
-            let normals: Vec<f32> = heli.body.normals;
-            setup_vao(&vek, &indices, &color, &normals)
-        };
-        let door_vao = unsafe {
-            let vek:     Vec<f32> = heli.door.vertices;
-            let indices: Vec<u32> = heli.door.indices;
-            let color:   Vec<f32> = heli.door.colors;
-            let normals: Vec<f32> = heli.door.normals;
-            setup_vao(&vek, &indices, &color, &normals)
-        };
-        let main_rotor_vao = unsafe {
-            let vek:     Vec<f32> = heli.main_rotor.vertices;
-            let indices: Vec<u32> = heli.main_rotor.indices;
-            let color:   Vec<f32> = heli.main_rotor.colors;
-            let normals: Vec<f32> = heli.main_rotor.normals;
-            setup_vao(&vek, &indices, &color, &normals)
-        };
-        let tail_rotor_vao = unsafe {
-            let vek:     Vec<f32> = heli.tail_rotor.vertices;
-            let indices: Vec<u32> = heli.tail_rotor.indices;
-            let color:   Vec<f32> = heli.tail_rotor.colors;
-            let normals: Vec<f32> = heli.tail_rotor.normals;
-            setup_vao(&vek, &indices, &color, &normals)
-        };
-
-        //setup the scene graph
+        //every mesh is packed into one interleaved buffer laid out the way the shader expects
+        let layout = VertexLayout::standard();
+
+        //load the models, leaving out whatever fails to load so the rest can still be shown
+        let terrain_mesh = mesh::Terrain::load("./resources/lunarsurface.obj")
+            .map_err(|e| eprintln!("{}", e))
+            .ok();
+        let heli = mesh::Helicopter::load("./resources/helicopter.obj")
+            .map_err(|e| eprintln!("{}", e))
+            .ok();
+
+        //set up the vaos, the gpu meshes have to outlive the nodes drawing them
+        let upload = |mesh: Option<&mesh::Mesh>| mesh.map(|m| unsafe { GpuMesh::upload(m, &layout) });
+        let heli_part = |name: &str| heli.as_ref().and_then(|h| h.get(name));
+        let terrain_gpu    = upload(terrain_mesh.as_ref());
+        let body_gpu       = upload(heli_part("Body_body"));
+        let door_gpu       = upload(heli_part("Door_door"));
+        let main_rotor_gpu = upload(heli_part("Main_Rotor_main_rotor"));
+        let tail_rotor_gpu = upload(heli_part("Tail_Rotor_tail_rotor"));
 
         //generate nodes for all objects
-        let mut root = SceneNode::new();
-        let mut terrain = SceneNode::from_vao(terrain_vao, mesh.index_count);
-        let mut body = SceneNode::from_vao(body_vao, heli.body.index_count);
-        let mut door = SceneNode::from_vao(door_vao, heli.door.index_count);
-        let mut main_rotor = SceneNode::from_vao(main_rotor_vao, heli.main_rotor.index_count);
-        let mut tail_rotor = SceneNode::from_vao(tail_rotor_vao, heli.tail_rotor.index_count);
+        let node = |gpu: &Option<GpuMesh>| match gpu {
+            Some(gpu) => SceneNode { vao_id: gpu.vao_id(), index_count: gpu.index_count(), primitive: gpu.primitive(), ..SceneNode::default() },
+            None => SceneNode::default(),
+        };
+        let mut graph = SceneGraph::new();
+        let terrain    = graph.add(node(&terrain_gpu));
+        let body       = graph.add(node(&body_gpu));
+        let door       = graph.add(node(&door_gpu));
+        let main_rotor = graph.add(node(&main_rotor_gpu));
+        let tail_rotor = graph.add(node(&tail_rotor_gpu));
 
         //set the reference points for the nodes
-        tail_rotor.reference_point = glm::vec3(0.35, 2.3, 10.4);
-        main_rotor.reference_point = glm::vec3(0.0, 2.3, 0.0);
-        body.reference_point       = glm::vec3(0.0, 0.0, 0.0);
+        graph[tail_rotor].reference_point = glm::vec3(0.35, 2.3, 10.4);
+        graph[main_rotor].reference_point = glm::vec3(0.0, 2.3, 0.0);
+        graph[body].reference_point       = glm::vec3(0.0, 0.0, 0.0);
 
         //organize the graph
-        root.add_child(&terrain);
-        terrain.add_child(&body);
-        body.add_child(&door);
-        body.add_child(&main_rotor);
-        body.add_child(&tail_rotor);
+        graph.add_child(terrain, body);
+        graph.add_child(body, door);
+        graph.add_child(body, main_rotor);
+        graph.add_child(body, tail_rotor);
 
 
 
@@ -348,28 +249,22 @@ fn main() {
                 //apply transformations
                 matrise=persp*trans*z_translation;
 
-                //transformations so far, to be updated by the traversal-function
-                let sofar: glm::Mat4 = glm::identity();
-
-                
                 //setup the animations
-                tail_rotor.rotation.x = elapsed*5.0;
-                main_rotor.rotation.y = elapsed*5.0;
+                graph[tail_rotor].set_axis_angle(&glm::vec3(1.0, 0.0, 0.0), elapsed*5.0);
+                graph[main_rotor].set_axis_angle(&glm::vec3(0.0, 1.0, 0.0), elapsed*5.0);
 
                 //get the Heading
                 let heading = toolbox::simple_heading_animation(elapsed);
 
                 //setup for animation
-                body.position.x = heading.x;
-                body.position.z = heading.z;
-                body.rotation.z = heading.roll;
-                body.rotation.y = heading.yaw;
-                body.rotation.x = heading.pitch;
+                graph[body].position.x = heading.x;
+                graph[body].position.z = heading.z;
+                graph[body].set_euler(&glm::vec3(heading.pitch, heading.yaw, heading.roll), EulerOrder::Zxy);
                 
 
                 //update and draw the scene graph
-                update_node_transformations(&mut root, &sofar);
-                draw_scene(&root, &matrise);
+                graph.update_transformations();
+                graph.draw(|node, model| draw_node(node, model, &matrise));
 
 
 
@@ -1,117 +1,777 @@
+extern crate nalgebra_glm as glm;
+
 use tobj;
+use std::fmt;
+use std::collections::HashMap;
+
+use crate::mesh_cache;
+use crate::optimize;
+
+// Everything that can go wrong when loading a model
+#[derive(Debug)]
+pub enum MeshError {
+    Load { path: String, error: tobj::LoadError },
+    NoMeshes { path: String },
+    MissingParts { path: String, parts: Vec<String> },
+    Gltf { path: String, error: gltf::Error },
+    Image { path: String, error: image::ImageError },
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::Load { path, error }
+                => write!(f, "Failed to load {}: {}", path, error),
+            MeshError::NoMeshes { path }
+                => write!(f, "{} contains no meshes", path),
+            MeshError::MissingParts { path, parts }
+                => write!(f, "{} is missing the parts: {}", path, parts.join(", ")),
+            MeshError::Gltf { path, error }
+                => write!(f, "Failed to load {}: {}", path, error),
+            MeshError::Image { path, error }
+                => write!(f, "Failed to load {}: {}", path, error),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
 
 // internal helper
 fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
     color.iter().cloned().cycle().take(num*4).collect()
 }
 
+// internal helper, tobj uses empty strings for texture maps that aren't set
+fn texture_name(name: &str) -> Option<String> {
+    if name.is_empty() { None } else { Some(name.to_string()) }
+}
+
+// Surface properties read from the MTL file accompanying an OBJ
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct Material {
+    pub name      : String,
+    pub ambient   : [f32; 3],
+    pub diffuse   : [f32; 3],
+    pub specular  : [f32; 3],
+    pub shininess : f32,
+    pub dissolve  : f32,
+
+    pub ambient_texture  : Option<String>,
+    pub diffuse_texture  : Option<String>,
+    pub specular_texture : Option<String>,
+    pub normal_texture   : Option<String>,
+}
+
+impl Material {
+    pub fn from(material: &tobj::Material) -> Self {
+        Material {
+            name      : material.name.clone(),
+            ambient   : material.ambient,
+            diffuse   : material.diffuse,
+            specular  : material.specular,
+            shininess : material.shininess,
+            dissolve  : material.dissolve,
+            ambient_texture  : texture_name(&material.ambient_texture),
+            diffuse_texture  : texture_name(&material.diffuse_texture),
+            specular_texture : texture_name(&material.specular_texture),
+            normal_texture   : texture_name(&material.normal_texture),
+        }
+    }
+
+    // The diffuse color, with the dissolve factor as alpha
+    pub fn color(&self) -> [f32; 4] {
+        [self.diffuse[0], self.diffuse[1], self.diffuse[2], self.dissolve]
+    }
+}
+
+// internal helper, looks up the material of a tobj mesh
+fn material_of(mesh: &tobj::Mesh, materials: &[tobj::Material]) -> Option<Material> {
+    mesh.material_id.and_then(|id| materials.get(id)).map(Material::from)
+}
+
+// internal helper, a missing MTL file shouldn't prevent the model itself from loading
+fn materials_or_empty(materials: Result<Vec<tobj::Material>, tobj::LoadError>, path: &str) -> Vec<tobj::Material> {
+    materials.unwrap_or_else(|e| {
+        println!("No materials loaded for {}: {}", path, e);
+        vec![]
+    })
+}
+
+// How to compute the normals of a mesh
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum NormalMode {
+    // Area-weighted average of the faces around each point, shared across duplicated vertices
+    Smooth,
+    // Only faces whose normals are within `angle_threshold` radians of each other are averaged,
+    // vertices on sharper edges are split. A threshold of 0 gives fully faceted normals.
+    Flat { angle_threshold: f32 },
+}
+
+// The extent of a mesh or a part of the scene, as both an axis-aligned box and a sphere
+#[derive(Clone, Copy, Debug)]
+pub struct Bounds {
+    pub min    : glm::Vec3,
+    pub max    : glm::Vec3,
+    pub center : glm::Vec3,   // Of the sphere, which isn't necessarily the center of the box
+    pub radius : f32,
+}
+
+impl Bounds {
+    // Bounds around a set of points, or a single point at the origin if there are none
+    pub fn from_points<I: IntoIterator<Item = glm::Vec3>>(points: I) -> Self {
+        let points: Vec<glm::Vec3> = points.into_iter().collect();
+        if points.is_empty() {
+            return Bounds { min: glm::zero(), max: glm::zero(), center: glm::zero(), radius: 0.0 };
+        }
+        let min = points.iter().fold(points[0], |m, p| glm::min2(&m, p));
+        let max = points.iter().fold(points[0], |m, p| glm::max2(&m, p));
+        let center = (min + max) / 2.0;
+        let radius = points.iter().map(|p| glm::distance(p, &center)).fold(0.0, f32::max);
+        Bounds { min, max, center, radius }
+    }
+
+    pub fn corners(&self) -> [glm::Vec3; 8] {
+        let (a, b) = (self.min, self.max);
+        [
+            glm::vec3(a.x, a.y, a.z), glm::vec3(b.x, a.y, a.z), glm::vec3(a.x, b.y, a.z), glm::vec3(b.x, b.y, a.z),
+            glm::vec3(a.x, a.y, b.z), glm::vec3(b.x, a.y, b.z), glm::vec3(a.x, b.y, b.z), glm::vec3(b.x, b.y, b.z),
+        ]
+    }
+
+    // The bounds after applying a transformation. The box is made to fit the transformed corners,
+    // and the sphere is scaled by the largest scaling along any axis.
+    pub fn transformed(&self, transform: &glm::Mat4) -> Self {
+        let apply = |p: &glm::Vec3| (transform * glm::vec4(p.x, p.y, p.z, 1.0)).xyz();
+        let corners: Vec<glm::Vec3> = self.corners().iter().map(apply).collect();
+        let max_scale = (0..3)
+            .map(|i| glm::length(&transform.column(i).xyz()))
+            .fold(0.0, f32::max);
+        Bounds {
+            min: corners.iter().fold(corners[0], |m, p| glm::min2(&m, p)),
+            max: corners.iter().fold(corners[0], |m, p| glm::max2(&m, p)),
+            center: apply(&self.center),
+            radius: self.radius * max_scale,
+        }
+    }
+
+    // The smallest bounds containing both
+    pub fn union(&self, other: &Bounds) -> Self {
+        let offset = other.center - self.center;
+        let distance = glm::length(&offset);
+        let (center, radius) = if distance + other.radius <= self.radius {
+            (self.center, self.radius)
+        } else if distance + self.radius <= other.radius {
+            (other.center, other.radius)
+        } else {
+            let radius = (distance + self.radius + other.radius) / 2.0;
+            (self.center + offset * ((radius - self.radius) / distance), radius)
+        };
+        Bounds {
+            min: glm::min2(&self.min, &other.min),
+            max: glm::max2(&self.max, &other.max),
+            center,
+            radius,
+        }
+    }
+}
+
+// A range of indices within a merged mesh, remembering which model it came from
+#[allow(dead_code)]
+#[derive(Clone)]
+pub struct SubMesh {
+    pub name        : String,
+    pub first_index : usize,
+    pub index_count : i32,
+    pub material    : Option<Material>,
+}
+
+#[derive(Clone)]
 pub struct Mesh {
     pub vertices: Vec<f32>,
     pub normals: Vec<f32>,
     pub colors: Vec<f32>,
+    pub uvs: Vec<f32>,
+    pub tangents: Vec<f32>,   // xyz and the handedness of the bitangent in w
+    pub bitangents: Vec<f32>,
     pub indices: Vec<u32>,
     pub index_count: i32,
+    pub submeshes: Vec<SubMesh>,
+    pub material: Option<Material>,
+    pub bounds: Bounds,
+    pub centroid: glm::Vec3,   // The average of the vertex positions
 }
 
 impl Mesh {
+    #[allow(dead_code)]
     pub fn from(mesh: tobj::Mesh, color: [f32; 4]) -> Self {
-        let num_verts = mesh.positions.len() / 3;
-        let index_count = mesh.indices.len() as i32;
-        Mesh {
-            vertices: mesh.positions,
-            normals: mesh.normals,
-            indices: mesh.indices,
+        Mesh::with_material(mesh, None, color)
+    }
+
+    // The vertices are colored by the diffuse color of the material, falling back to the given
+    // flat color if the mesh has no material
+    pub fn with_material(mesh: tobj::Mesh, material: Option<Material>, fallback_color: [f32; 4]) -> Self {
+        let color = material.as_ref().map_or(fallback_color, Material::color);
+        let mut result = Mesh::new(mesh.positions, mesh.normals, mesh.texcoords, mesh.indices, color);
+        result.material = material;
+        result
+    }
+
+    // Builds a mesh from raw vertex data, painted a single color. Normals are generated if
+    // `normals` is empty, while `uvs` may be left empty if the mesh isn't textured.
+    pub fn new(vertices: Vec<f32>, normals: Vec<f32>, uvs: Vec<f32>, indices: Vec<u32>, color: [f32; 4]) -> Self {
+        let num_verts = vertices.len() / 3;
+        let index_count = indices.len() as i32;
+        let mut mesh = Mesh {
+            vertices,
+            normals,
             colors: generate_color_vec(color, num_verts),
+            uvs,
+            tangents: vec![],
+            bitangents: vec![],
+            indices,
             index_count,
+            submeshes: vec![],
+            material: None,
+            bounds: Bounds::from_points(vec![]),
+            centroid: glm::zero(),
+        };
+        if mesh.normals.is_empty() {
+            mesh.compute_normals(NormalMode::Smooth);
+        }
+        mesh.update_bounds();
+        mesh
+    }
+
+    // Recomputes `bounds` and `centroid`, needed if the vertices are changed by hand
+    pub fn update_bounds(&mut self) {
+        let num_verts = (self.vertices.len() / 3) as u32;
+        self.bounds = Bounds::from_points((0..num_verts).map(|i| self.position(i)));
+        self.centroid = if num_verts > 0 {
+            (0..num_verts).fold(glm::zero(), |sum: glm::Vec3, i| sum + self.position(i)) / num_verts as f32
+        } else {
+            glm::zero()
+        };
+    }
+
+    pub fn position(&self, i: u32) -> glm::Vec3 {
+        let i = i as usize * 3;
+        glm::vec3(self.vertices[i], self.vertices[i + 1], self.vertices[i + 2])
+    }
+
+    // Rebuilds every per-vertex attribute so that vertex `i` becomes a copy of vertex `sources[i]`
+    pub fn remap_vertices(&mut self, sources: &[u32]) {
+        fn gather(data: &[f32], n: usize, sources: &[u32]) -> Vec<f32> {
+            sources.iter().flat_map(|&s| data[s as usize * n..(s as usize + 1) * n].iter().cloned()).collect()
+        }
+        self.vertices = gather(&self.vertices, 3, sources);
+        self.colors = gather(&self.colors, 4, sources);
+        if !self.normals.is_empty() {
+            self.normals = gather(&self.normals, 3, sources);
+        }
+        if !self.uvs.is_empty() {
+            self.uvs = gather(&self.uvs, 2, sources);
+        }
+        if !self.tangents.is_empty() {
+            self.tangents = gather(&self.tangents, 4, sources);
+            self.bitangents = gather(&self.bitangents, 3, sources);
+        }
+    }
+
+    // Computes per-vertex tangents and bitangents from the texture coordinates. Like MikkTSpace, the
+    // tangents of the triangles around a vertex are weighted by the angle of their corner and
+    // orthogonalized against the normal, and the sign in `w` tells whether the bitangent is
+    // cross(normal, tangent) or its negation. It is not MikkTSpace though: vertices are not split
+    // where the mapping is mirrored, so normal maps baked with MikkTSpace can show seams there.
+    // Returns false if the mesh has no texture coordinates to derive them from.
+    pub fn compute_tangents(&mut self) -> bool {
+        if self.uvs.is_empty() {
+            return false;
+        }
+        if self.normals.is_empty() {
+            self.compute_normals(NormalMode::Smooth);
+        }
+
+        let num_verts = self.vertices.len() / 3;
+        let uv = |i: u32| glm::vec2(self.uvs[i as usize * 2], self.uvs[i as usize * 2 + 1]);
+        let mut tan_sums = vec![glm::Vec3::zeros(); num_verts];
+        let mut bitan_sums = vec![glm::Vec3::zeros(); num_verts];
+        for t in self.indices.chunks(3) {
+            let (p0, p1, p2) = (self.position(t[0]), self.position(t[1]), self.position(t[2]));
+            let (uv0, uv1, uv2) = (uv(t[0]), uv(t[1]), uv(t[2]));
+            let (e1, e2) = (p1 - p0, p2 - p0);
+            let (d1, d2) = (uv1 - uv0, uv2 - uv0);
+
+            let det = d1.x * d2.y - d2.x * d1.y;
+            if det.abs() < 1e-12 {
+                continue; // degenerate texture mapping, this triangle has nothing to contribute
+            }
+            // The direction is all that matters, the weight is the angle of each corner
+            let tangent = e1 * d2.y - e2 * d1.y;
+            let bitangent = e2 * d1.x - e1 * d2.x;
+            if glm::length(&tangent) < 1e-12 || glm::length(&bitangent) < 1e-12 {
+                continue;
+            }
+            let (tangent, bitangent) = (glm::normalize(&tangent) * det.signum(), glm::normalize(&bitangent) * det.signum());
+            let corners = [(p0, p1, p2), (p1, p2, p0), (p2, p0, p1)];
+            for (&v, &(p, next, prev)) in t.iter().zip(&corners) {
+                let (a, b) = (next - p, prev - p);
+                if glm::length(&a) < 1e-12 || glm::length(&b) < 1e-12 {
+                    continue;
+                }
+                let angle = glm::dot(&glm::normalize(&a), &glm::normalize(&b)).clamp(-1.0, 1.0).acos();
+                tan_sums[v as usize] += tangent * angle;
+                bitan_sums[v as usize] += bitangent * angle;
+            }
+        }
+
+        self.tangents = Vec::with_capacity(num_verts * 4);
+        self.bitangents = Vec::with_capacity(num_verts * 3);
+        for v in 0..num_verts {
+            let n = glm::vec3(self.normals[v * 3], self.normals[v * 3 + 1], self.normals[v * 3 + 2]);
+            let mut t = tan_sums[v] - n * glm::dot(&n, &tan_sums[v]);
+            if glm::length(&t) < 1e-12 {
+                // No usable mapping around this vertex, any vector orthogonal to the normal will do
+                let axis = if n.x.abs() < 0.9 { glm::vec3(1.0, 0.0, 0.0) } else { glm::vec3(0.0, 1.0, 0.0) };
+                t = axis - n * glm::dot(&n, &axis);
+            }
+            let t = glm::normalize(&t);
+            let w = if glm::dot(&glm::cross(&n, &t), &bitan_sums[v]) < 0.0 { -1.0 } else { 1.0 };
+            let b = glm::cross(&n, &t) * w;
+            self.tangents.extend_from_slice(&[t.x, t.y, t.z, w]);
+            self.bitangents.extend_from_slice(&[b.x, b.y, b.z]);
+        }
+        true
+    }
+
+    // Gets the mesh ready to be drawn with the normal map, if there is one, by computing its
+    // tangents. Normal mapping needs tangents, which can only be made if the mesh has uvs, so
+    // without them the map is dropped and the mesh is drawn with its plain normals.
+    pub fn with_normal_map<T>(&mut self, normal_map: Option<T>) -> Option<T> {
+        normal_map.filter(|_| self.compute_tangents())
+    }
+
+    // Replaces the normals of the mesh. The flat mode may split vertices, but the triangle order is
+    // left untouched so submesh ranges stay valid.
+    pub fn compute_normals(&mut self, mode: NormalMode) {
+        // Vertices sharing a position are treated as the same point, so that seams from
+        // duplicated vertices don't show up in the shading
+        let mut point_ids: HashMap<[u32; 3], usize> = HashMap::new();
+        let points: Vec<usize> = self.vertices.chunks(3)
+            .map(|p| {
+                let next = point_ids.len();
+                *point_ids.entry([p[0].to_bits(), p[1].to_bits(), p[2].to_bits()]).or_insert(next)
+            })
+            .collect();
+
+        // The length of the cross product is twice the area of the triangle, which gives us the
+        // area weighting for free
+        let face_normals: Vec<glm::Vec3> = self.indices.chunks(3)
+            .map(|t| {
+                let (a, b, c) = (self.position(t[0]), self.position(t[1]), self.position(t[2]));
+                glm::cross(&(b - a), &(c - a))
+            })
+            .collect();
+
+        let normalize = |n: glm::Vec3| {
+            if glm::length(&n) > 0.0 { glm::normalize(&n) } else { glm::vec3(0.0, 1.0, 0.0) }
+        };
+
+        match mode {
+            NormalMode::Smooth => {
+                let mut sums = vec![glm::Vec3::zeros(); point_ids.len()];
+                for (t, n) in self.indices.chunks(3).zip(&face_normals) {
+                    for &v in t {
+                        sums[points[v as usize]] += n;
+                    }
+                }
+                self.normals = points.iter()
+                    .flat_map(|&p| { let n = normalize(sums[p]); vec![n.x, n.y, n.z] })
+                    .collect();
+            },
+            NormalMode::Flat { angle_threshold } => {
+                let min_cos = angle_threshold.cos();
+                let mut faces_around = vec![vec![]; point_ids.len()];
+                for (f, t) in self.indices.chunks(3).enumerate() {
+                    for &v in t {
+                        faces_around[points[v as usize]].push(f);
+                    }
+                }
+
+                // Every corner gets its own normal, corners of the same vertex that end up with the
+                // same normal are welded back together
+                let mut sources = vec![];
+                let mut normals = vec![];
+                let mut corner_ids: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+                for i in 0..self.indices.len() {
+                    let v = self.indices[i];
+                    let face = normalize(face_normals[i / 3]);
+                    // The corner's own face always counts, as with a threshold of 0 rounding can
+                    // put it just outside of the cone around itself
+                    let n = normalize(faces_around[points[v as usize]].iter()
+                        .filter(|&&f| f == i / 3 || glm::dot(&normalize(face_normals[f]), &face) >= min_cos)
+                        .fold(glm::Vec3::zeros(), |sum, &f| sum + face_normals[f]));
+                    let key = (v, [n.x.to_bits(), n.y.to_bits(), n.z.to_bits()]);
+                    self.indices[i] = *corner_ids.entry(key).or_insert_with(|| {
+                        sources.push(v);
+                        normals.extend_from_slice(&[n.x, n.y, n.z]);
+                        (sources.len() - 1) as u32
+                    });
+                }
+                self.normals = vec![];
+                self.remap_vertices(&sources);
+                self.normals = normals;
+                // Vertices no triangle used are gone, which moves the centroid
+                self.update_bounds();
+            },
         }
     }
+
+    // Concatenates several meshes into one, offsetting the indices of each mesh by the number of
+    // vertices that came before it. If `keep_submeshes` is set, the index range of every input
+    // mesh is recorded in `submeshes` so the parts can still be drawn separately.
+    // The merged mesh takes on the material of the first mesh that has one.
+    pub fn merge(meshes: Vec<(String, Mesh)>, keep_submeshes: bool) -> Self {
+        let mut merged = Mesh::new(vec![], vec![], vec![], vec![], [1.0, 1.0, 1.0, 1.0]);
+
+        // If only some of the meshes have an optional attribute, the rest are padded with zeros
+        fn extend_or_pad(merged: &mut Vec<f32>, data: Vec<f32>, n: usize, num_verts: usize, any: bool) {
+            if !any {
+                return;
+            }
+            if data.is_empty() {
+                merged.resize(merged.len() + num_verts * n, 0.0);
+            } else {
+                merged.extend(data);
+            }
+        }
+        let any_uvs = meshes.iter().any(|(_, mesh)| !mesh.uvs.is_empty());
+        let any_tangents = meshes.iter().any(|(_, mesh)| !mesh.tangents.is_empty());
+
+        for (name, mesh) in meshes {
+            let base_vertex = (merged.vertices.len() / 3) as u32;
+            let first_index = merged.indices.len();
+            let num_verts = mesh.vertices.len() / 3;
+
+            extend_or_pad(&mut merged.uvs, mesh.uvs, 2, num_verts, any_uvs);
+            extend_or_pad(&mut merged.tangents, mesh.tangents, 4, num_verts, any_tangents);
+            extend_or_pad(&mut merged.bitangents, mesh.bitangents, 3, num_verts, any_tangents);
+            merged.vertices.extend(mesh.vertices);
+            merged.normals.extend(mesh.normals);
+            merged.colors.extend(mesh.colors);
+            merged.indices.extend(mesh.indices.iter().map(|i| i + base_vertex));
+
+            if merged.material.is_none() {
+                merged.material = mesh.material.clone();
+            }
+            if keep_submeshes {
+                merged.submeshes.push(SubMesh {
+                    name,
+                    first_index,
+                    index_count: mesh.index_count,
+                    material: mesh.material,
+                });
+            }
+        }
+
+        merged.index_count = merged.indices.len() as i32;
+        merged.update_bounds();
+        merged
+    }
 }
 
+// Vertices are merged when optimizing a terrain if every attribute rounds to the same multiple of this
+const WELD_TOLERANCE: f32 = 1e-5;
+
 pub struct Terrain;
 impl Terrain {
-    pub fn load(path: &str) -> Mesh {
+    // Parsing the OBJ is slow, so the mesh is optimized and stored in a binary cache next to it
+    // the first time it is loaded. The cache is used on later loads for as long as the OBJ and
+    // its .mtl files stay the same.
+    pub fn load(path: &str) -> Result<Mesh, MeshError> {
+        let cache = mesh_cache::cache_path(path);
+        let source_hash = mesh_cache::hash_model(path).ok();
+
+        if let Some(hash) = source_hash {
+            let before = std::time::Instant::now();
+            match mesh_cache::read(&cache, hash) {
+                Ok(Some(mesh)) => {
+                    let after = std::time::Instant::now();
+                    println!("Loaded terrain from {} in {:.3}ms.", cache.display(), after.duration_since(before).as_micros() as f32 / 1e3);
+                    return Ok(mesh);
+                },
+                Ok(None) => println!("Terrain cache is out of date, reloading {}", path),
+                Err(_)   => { }, // No usable cache yet
+            }
+        }
+
+        let mut mesh = Terrain::load_merged(path, false)?;
+        println!("Optimized terrain: {}", optimize::optimize(&mut mesh, WELD_TOLERANCE));
+        if let Some(hash) = source_hash {
+            if let Err(e) = mesh_cache::write(&cache, &mesh, hash) {
+                println!("Failed to write terrain cache {}: {}", cache.display(), e);
+            }
+        }
+        Ok(mesh)
+    }
+
+    // Builds a terrain from a grayscale image, with one vertex per pixel laid out in the XZ plane
+    // `spacing` units apart and centered on the origin. Black is at height 0 and white at
+    // `height_scale`. The UVs, if wanted, stretch a texture once across the whole terrain.
+    #[allow(dead_code)]
+    pub fn from_heightmap(path: &str, spacing: f32, height_scale: f32, with_uvs: bool) -> Result<Mesh, MeshError> {
+        let image = image::open(path)
+            .map_err(|error| MeshError::Image { path: path.to_string(), error })?
+            .into_luma16();
+        let (w, h) = (image.width() as usize, image.height() as usize);
+        let height = |x: usize, z: usize| image.get_pixel(x as u32, z as u32)[0] as f32 / 65535.0 * height_scale;
+
+        let mut vertices = Vec::with_capacity(w * h * 3);
+        let mut normals = Vec::with_capacity(w * h * 3);
+        let mut uvs = Vec::with_capacity(if with_uvs { w * h * 2 } else { 0 });
+        for z in 0..h {
+            for x in 0..w {
+                vertices.push((x as f32 - (w - 1) as f32 / 2.0) * spacing);
+                vertices.push(height(x, z));
+                vertices.push((z as f32 - (h - 1) as f32 / 2.0) * spacing);
+
+                // Central differences, falling back to one-sided ones along the edges
+                let (x0, x1) = (x.saturating_sub(1), (x + 1).min(w - 1));
+                let (z0, z1) = (z.saturating_sub(1), (z + 1).min(h - 1));
+                let dx = (height(x1, z) - height(x0, z)) / ((x1 - x0).max(1) as f32 * spacing);
+                let dz = (height(x, z1) - height(x, z0)) / ((z1 - z0).max(1) as f32 * spacing);
+                let n = glm::normalize(&glm::vec3(-dx, 1.0, -dz));
+                normals.extend_from_slice(&[n.x, n.y, n.z]);
+
+                if with_uvs {
+                    uvs.push(x as f32 / (w - 1).max(1) as f32);
+                    uvs.push(z as f32 / (h - 1).max(1) as f32);
+                }
+            }
+        }
+
+        // Two counter-clockwise (seen from above) triangles per grid cell
+        let mut indices = Vec::with_capacity(w.saturating_sub(1) * h.saturating_sub(1) * 6);
+        for z in 0..h.saturating_sub(1) {
+            for x in 0..w.saturating_sub(1) {
+                let i = (z * w + x) as u32;
+                let (right, down, diagonal) = (i + 1, i + w as u32, i + w as u32 + 1);
+                indices.extend_from_slice(&[i, down, right, right, down, diagonal]);
+            }
+        }
+
+        println!("Generated terrain from {} with {} points and {} triangles.", path, w * h, indices.len() / 3);
+        Ok(Mesh::new(vertices, normals, uvs, indices, [1.0, 1.0, 1.0, 1.0]))
+    }
+
+    // Loads a terrain that may be split into several models (e.g. chunked Blender exports),
+    // merging all of them into a single mesh
+    #[allow(dead_code)]
+    pub fn load_merged(path: &str, keep_submeshes: bool) -> Result<Mesh, MeshError> {
         println!("Loading terrain model...");
         let before = std::time::Instant::now();
-        let (models, _materials)
+        let (models, materials)
             = tobj::load_obj(path,
                 &tobj::LoadOptions{
                     triangulate: true,
                     single_index: true,
                     ..Default::default()
                 }
-            ).expect("Failed to load terrain model");
+            ).map_err(|error| MeshError::Load { path: path.to_string(), error })?;
+        let materials = materials_or_empty(materials, path);
         let after = std::time::Instant::now();
         println!("Done in {:.3}ms.", after.duration_since(before).as_micros() as f32 / 1e3);
 
-        if models.len() > 1 || models.len() == 0 {
-            panic!("Please use a model with a single mesh!")
-            // You could try merging the vertices and indices
-            // of the separate meshes into a single mesh.
-            // I'll leave that as an optional exercise. ;)
+        if models.is_empty() {
+            return Err(MeshError::NoMeshes { path: path.to_string() });
         }
 
-        let terrain = models[0].to_owned();
-        println!("Loaded {} with {} points and {} triangles.",
-            terrain.name,
-            terrain.mesh.positions.len() /3,
-            terrain.mesh.indices.len() / 3,
-        );
+        for model in &models {
+            println!("Loaded {} with {} points and {} triangles.",
+                model.name,
+                model.mesh.positions.len() /3,
+                model.mesh.indices.len() / 3,
+            );
+        }
 
-        Mesh::from(terrain.mesh, [1.0, 1.0, 1.0, 1.0])
+        let parts = models.into_iter()
+            .map(|model| {
+                let material = material_of(&model.mesh, &materials);
+                (model.name, Mesh::with_material(model.mesh, material, [1.0, 1.0, 1.0, 1.0]))
+            })
+            .collect();
+
+        Ok(Mesh::merge(parts, keep_submeshes))
     }
 }
 
 use std::ops::Index;
-pub struct Helicopter {
-    pub body       : Mesh,
-    pub door       : Mesh,
-    pub main_rotor : Mesh,
-    pub tail_rotor : Mesh,
-}
 
-// You can use square brackets to access the components of the helicopter, if you want to use loops!
-impl Index<usize> for Helicopter {
-    type Output = Mesh;
-    fn index<'a>(&'a self, i: usize) -> &'a Mesh {
-        match i {
-            0 => &self.body,
-            1 => &self.main_rotor,
-            2 => &self.tail_rotor,
-            3 => &self.door,
-            _ => panic!("Invalid index, try [0,3]"),
-        }
-    }
+// A model made up of named parts, e.g. the body, door and rotors of a vehicle.
+// The parts are kept in the order they appear in the file.
+pub struct Model {
+    pub parts: Vec<(String, Mesh)>,
+    lookup: HashMap<String, usize>,
 }
 
-impl Helicopter {
-    pub fn load(path: &str) -> Self {
-        println!("Loading helicopter model...");
+impl Model {
+    // Loads every object in the OBJ file as a separate part. `part_colors` maps object names to
+    // the color the part should be painted with if it has no material, and every name listed there
+    // must be present in the file. Objects in neither are painted with `default_color`.
+    pub fn load(path: &str, part_colors: &[(&str, [f32; 4])], default_color: [f32; 4]) -> Result<Self, MeshError> {
+        println!("Loading model {}...", path);
         let before = std::time::Instant::now();
-        let (models, _materials)
+        let (models, materials)
             = tobj::load_obj(path,
                 &tobj::LoadOptions{
                     triangulate: true,
                     single_index: true,
                     ..Default::default()
                 }
-            ).expect("Failed to load helicopter model");
+            ).map_err(|error| MeshError::Load { path: path.to_string(), error })?;
+        let materials = materials_or_empty(materials, path);
         let after = std::time::Instant::now();
         println!("Done in {:.3}ms!", after.duration_since(before).as_micros() as f32 / 1e3);
 
-        for model in &models {
-            println!("Loaded {} with {} points and {} triangles.", model.name, model.mesh.positions.len() / 3, model.mesh.indices.len() / 3);
+        let missing: Vec<String> = part_colors.iter()
+            .map(|&(name, _)| name)
+            .filter(|name| !models.iter().any(|m| m.name == *name))
+            .map(String::from)
+            .collect();
+        if !missing.is_empty() {
+            return Err(MeshError::MissingParts { path: path.to_string(), parts: missing });
+        }
+
+        let mut model = Model {
+            parts: Vec::with_capacity(models.len()),
+            lookup: HashMap::new(),
+        };
+        for m in models {
+            println!("Loaded {} with {} points and {} triangles.", m.name, m.mesh.positions.len() / 3, m.mesh.indices.len() / 3);
+            let color = part_colors.iter()
+                .find(|&&(name, _)| name == m.name)
+                .map_or(default_color, |&(_, color)| color);
+            model.lookup.insert(m.name.clone(), model.parts.len());
+            let material = material_of(&m.mesh, &materials);
+            model.parts.push((m.name, Mesh::with_material(m.mesh, material, color)));
+        }
+
+        Ok(model)
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, name: &str) -> Option<&Mesh> {
+        self.lookup.get(name).map(|&i| &self.parts[i].1)
+    }
+
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Mesh)> {
+        self.parts.iter().map(|(name, mesh)| (name.as_str(), mesh))
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.parts.len()
+    }
+}
+
+// You can use square brackets to access the parts of a model by index. Parts are looked up by
+// name with get, as a name from a file may well be misspelled.
+impl Index<usize> for Model {
+    type Output = Mesh;
+    fn index(&self, i: usize) -> &Mesh {
+        &self.parts[i].1
+    }
+}
+
+#[allow(dead_code)]
+pub struct Helicopter;
+#[allow(dead_code)]
+impl Helicopter {
+    pub const PARTS: [(&'static str, [f32; 4]); 4] = [
+        ("Body_body",             [0.3, 0.3, 0.3, 1.0]),
+        ("Main_Rotor_main_rotor", [0.3, 0.1, 0.1, 1.0]),
+        ("Tail_Rotor_tail_rotor", [0.1, 0.3, 0.1, 1.0]),
+        ("Door_door",             [0.1, 0.1, 0.3, 1.0]),
+    ];
+
+    pub fn load(path: &str) -> Result<Model, MeshError> {
+        Model::load(path, &Helicopter::PARTS, [1.0, 1.0, 1.0, 1.0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives;
+
+    // Every corner's normal should face the same way as its triangle, going by the winding
+    fn assert_normals_follow_winding(mesh: &Mesh) {
+        for t in mesh.indices.chunks(3) {
+            let (a, b, c) = (mesh.position(t[0]), mesh.position(t[1]), mesh.position(t[2]));
+            let face = glm::cross(&(b - a), &(c - a));
+            if glm::length(&face) < 1e-9 {
+                continue; // degenerate, e.g. at the poles of a sphere
+            }
+            for &v in t {
+                let v = v as usize;
+                let n = glm::vec3(mesh.normals[v * 3], mesh.normals[v * 3 + 1], mesh.normals[v * 3 + 2]);
+                assert!(glm::dot(&n, &face) > 0.0, "normal {:?} faces away from {:?}", n, face);
+            }
+        }
+    }
+
+    #[test]
+    fn tangents_follow_the_uvs() {
+        // u runs along X and v along Z, with the normal pointing up
+        let mut plane = primitives::plane(2.0, 2.0, 3, 3, [1.0, 1.0, 1.0, 1.0]);
+        assert!(plane.compute_tangents());
+        for (t, b) in plane.tangents.chunks(4).zip(plane.bitangents.chunks(3)) {
+            assert!((t[0] - 1.0).abs() < 1e-5 && t[1].abs() < 1e-5 && t[2].abs() < 1e-5, "tangent {:?}", t);
+            assert_eq!(t[3], -1.0);
+            assert!(b[0].abs() < 1e-5 && b[1].abs() < 1e-5 && (b[2] - 1.0).abs() < 1e-5, "bitangent {:?}", b);
         }
+    }
 
-        let body_model = models.iter().find(|m| m.name == "Body_body").expect("Incorrect model file!").to_owned();
-        let door_model = models.iter().find(|m| m.name == "Door_door").expect("Incorrect model file!").to_owned();
-        let main_rotor_model = models.iter().find(|m| m.name == "Main_Rotor_main_rotor").expect("Incorrect model file!").to_owned();
-        let tail_rotor_model = models.iter().find(|m| m.name == "Tail_Rotor_tail_rotor").expect("Incorrect model file!").to_owned();
+    #[test]
+    fn tangents_are_orthonormal() {
+        let mut sphere = primitives::uv_sphere(1.0, 16, 8, [1.0, 1.0, 1.0, 1.0]);
+        assert!(sphere.compute_tangents());
+        for v in 0..sphere.vertices.len() / 3 {
+            let n = glm::make_vec3(&sphere.normals[v * 3..v * 3 + 3]);
+            let t = glm::make_vec3(&sphere.tangents[v * 4..v * 4 + 3]);
+            assert!((glm::length(&t) - 1.0).abs() < 1e-4);
+            assert!(glm::dot(&n, &t).abs() < 1e-4);
+        }
+    }
 
-        Helicopter {
-            body:       Mesh::from(body_model.mesh,         [0.3, 0.3, 0.3, 1.0]),
-            door:       Mesh::from(door_model.mesh,         [0.1, 0.1, 0.3, 1.0]),
-            main_rotor: Mesh::from(main_rotor_model.mesh,   [0.3, 0.1, 0.1, 1.0]),
-            tail_rotor: Mesh::from(tail_rotor_model.mesh,   [0.1, 0.3, 0.1, 1.0]),
+    #[test]
+    fn no_tangents_without_uvs() {
+        let mut mesh = Mesh::new(vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0], vec![], vec![], vec![0, 1, 2], [1.0; 4]);
+        assert!(!mesh.compute_tangents());
+        assert!(mesh.tangents.is_empty());
+    }
+
+    #[test]
+    fn faceted_normals_follow_winding() {
+        let white = [1.0, 1.0, 1.0, 1.0];
+        for mut mesh in [
+            primitives::uv_sphere(1.0, 16, 8, white),
+            primitives::icosphere(1.0, 2, white),
+            primitives::torus(1.0, 0.3, 16, 8, white),
+        ] {
+            mesh.compute_normals(NormalMode::Flat { angle_threshold: 0.0 });
+            assert_normals_follow_winding(&mesh);
         }
     }
+
+    #[test]
+    fn flat_normals_keep_smooth_surfaces_welded() {
+        let mut sphere = primitives::uv_sphere(1.0, 16, 8, [1.0, 1.0, 1.0, 1.0]);
+        let faceted_vertices = sphere.indices.len();
+        sphere.compute_normals(NormalMode::Flat { angle_threshold: 1.0 });
+        assert_normals_follow_winding(&sphere);
+        assert!(sphere.vertices.len() / 3 < faceted_vertices);
+    }
 }
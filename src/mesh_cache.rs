@@ -0,0 +1,315 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::mesh::{Material, Mesh};
+
+// A cache file starts with the magic bytes, the format version and the hash of the file it was
+// made from. After that come the vertex arrays, each prefixed by its length, and the material.
+// Bump VERSION whenever the layout changes, so old caches are thrown away instead of misread.
+const MAGIC: &[u8; 4] = b"GLMC";
+const VERSION: u32 = 2;
+
+// The cache lives next to the model, e.g. "lunarsurface.obj" is cached in "lunarsurface.obj.cache"
+pub fn cache_path(source: &str) -> PathBuf {
+    let mut path = Path::new(source).as_os_str().to_owned();
+    path.push(".cache");
+    PathBuf::from(path)
+}
+
+// 64 bit FNV-1a, continuing from `hash`. Unlike the std hashers it is guaranteed to stay the same
+// between Rust versions, so a toolchain update doesn't invalidate every cache.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+// Hash of an .obj file together with the material libraries it names, since the cache stores the
+// material too. A library that can't be read is hashed as missing, the same way tobj skips it.
+pub fn hash_model(path: &str) -> io::Result<u64> {
+    let obj = fs::read(path)?;
+    let mut hash = fnv1a(0xcbf29ce484222325, &obj);
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    for line in String::from_utf8_lossy(&obj).lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("mtllib") {
+            continue;
+        }
+        for library in words {
+            hash = fnv1a(hash, library.as_bytes());
+            match fs::read(dir.join(library)) {
+                Ok(bytes) => {
+                    hash = fnv1a(hash, &[1]);
+                    hash = fnv1a(hash, &bytes);
+                },
+                Err(_) => hash = fnv1a(hash, &[0]),
+            }
+        }
+    }
+    Ok(hash)
+}
+
+// internal helpers for writing
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_f32s(buf: &mut Vec<u8>, values: &[f32]) {
+    put_u32(buf, values.len() as u32);
+    for v in values {
+        buf.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, value: Option<&String>) {
+    match value {
+        Some(s) => {
+            put_u32(buf, s.len() as u32 + 1);
+            buf.extend_from_slice(s.as_bytes());
+        },
+        None => put_u32(buf, 0),
+    }
+}
+
+pub fn write(path: &Path, mesh: &Mesh, source_hash: u64) -> io::Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    put_u32(&mut buf, VERSION);
+    buf.extend_from_slice(&source_hash.to_le_bytes());
+
+    put_f32s(&mut buf, &mesh.vertices);
+    put_f32s(&mut buf, &mesh.normals);
+    put_f32s(&mut buf, &mesh.colors);
+    put_f32s(&mut buf, &mesh.uvs);
+    put_u32(&mut buf, mesh.indices.len() as u32);
+    for i in &mesh.indices {
+        buf.extend_from_slice(&i.to_le_bytes());
+    }
+
+    match &mesh.material {
+        Some(m) => {
+            buf.push(1);
+            put_string(&mut buf, Some(&m.name));
+            put_f32s(&mut buf, &m.ambient);
+            put_f32s(&mut buf, &m.diffuse);
+            put_f32s(&mut buf, &m.specular);
+            put_f32s(&mut buf, &[m.shininess, m.dissolve]);
+            put_string(&mut buf, m.ambient_texture.as_ref());
+            put_string(&mut buf, m.diffuse_texture.as_ref());
+            put_string(&mut buf, m.specular_texture.as_ref());
+            put_string(&mut buf, m.normal_texture.as_ref());
+        },
+        None => buf.push(0),
+    }
+
+    fs::write(path, buf)
+}
+
+// internal helper for reading, keeps track of where we are in the file
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.pos + n > self.bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Mesh cache is truncated"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn u32s(&mut self) -> io::Result<Vec<u32>> {
+        let n = self.u32()? as usize;
+        Ok(self.take(n * 4)?.chunks(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+    }
+
+    fn f32s(&mut self) -> io::Result<Vec<f32>> {
+        Ok(self.u32s()?.into_iter().map(f32::from_bits).collect())
+    }
+
+    fn f32_array(&mut self) -> io::Result<[f32; 3]> {
+        let v = self.f32s()?;
+        if v.len() != 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Mesh cache is corrupt"));
+        }
+        Ok([v[0], v[1], v[2]])
+    }
+
+    fn string(&mut self) -> io::Result<Option<String>> {
+        let n = self.u32()? as usize;
+        if n == 0 {
+            return Ok(None);
+        }
+        let bytes = self.take(n - 1)?;
+        Ok(Some(String::from_utf8_lossy(bytes).to_string()))
+    }
+}
+
+// Reads a cached mesh, returning None if the cache is from another version of the format or was
+// made from a different file than the one with hash `source_hash`
+pub fn read(path: &Path, source_hash: u64) -> io::Result<Option<Mesh>> {
+    let bytes = fs::read(path)?;
+    let mut r = Reader { bytes: &bytes, pos: 0 };
+    if r.take(4)? != MAGIC || r.u32()? != VERSION || r.u64()? != source_hash {
+        return Ok(None);
+    }
+
+    let vertices = r.f32s()?;
+    let normals = r.f32s()?;
+    let colors = r.f32s()?;
+    let uvs = r.f32s()?;
+    let indices = r.u32s()?;
+
+    let num_verts = vertices.len() / 3;
+    if normals.len() != num_verts * 3 || colors.len() != num_verts * 4
+        || !(uvs.is_empty() || uvs.len() == num_verts * 2)
+        || indices.iter().any(|&i| i as usize >= num_verts)
+    {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Mesh cache is corrupt"));
+    }
+
+    let material = if r.u8()? == 1 {
+        let name = r.string()?.unwrap_or_default();
+        let ambient = r.f32_array()?;
+        let diffuse = r.f32_array()?;
+        let specular = r.f32_array()?;
+        let rest = r.f32s()?;
+        if rest.len() != 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Mesh cache is corrupt"));
+        }
+        Some(Material {
+            name,
+            ambient,
+            diffuse,
+            specular,
+            shininess : rest[0],
+            dissolve  : rest[1],
+            ambient_texture  : r.string()?,
+            diffuse_texture  : r.string()?,
+            specular_texture : r.string()?,
+            normal_texture   : r.string()?,
+        })
+    } else {
+        None
+    };
+
+    let mut mesh = Mesh::new(vertices, normals, uvs, indices, [1.0, 1.0, 1.0, 1.0]);
+    mesh.colors = colors;
+    mesh.material = material;
+    Ok(Some(mesh))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives;
+
+    // A fresh directory under the system temp dir, so tests running in parallel don't collide
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gloom-mesh-cache-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn test_mesh() -> Mesh {
+        let mut mesh = primitives::cube(2.0, [0.5, 0.25, 1.0, 1.0]);
+        mesh.material = Some(Material {
+            name             : "metal".to_string(),
+            ambient          : [0.1, 0.1, 0.1],
+            diffuse          : [0.5, 0.6, 0.7],
+            specular         : [1.0, 1.0, 1.0],
+            shininess        : 32.0,
+            dissolve         : 1.0,
+            ambient_texture  : None,
+            diffuse_texture  : Some("metal.png".to_string()),
+            specular_texture : None,
+            normal_texture   : Some("".to_string()),
+        });
+        mesh
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_dir("round_trip").join("cube.obj.cache");
+        let mesh = test_mesh();
+        write(&path, &mesh, 42).unwrap();
+        let read = read(&path, 42).unwrap().expect("cache should be up to date");
+
+        assert_eq!(read.vertices, mesh.vertices);
+        assert_eq!(read.normals, mesh.normals);
+        assert_eq!(read.colors, mesh.colors);
+        assert_eq!(read.uvs, mesh.uvs);
+        assert_eq!(read.indices, mesh.indices);
+        let (m, expected) = (read.material.unwrap(), mesh.material.unwrap());
+        assert_eq!(m.name, expected.name);
+        assert_eq!(m.diffuse, expected.diffuse);
+        assert_eq!((m.shininess, m.dissolve), (expected.shininess, expected.dissolve));
+        assert_eq!(m.ambient_texture, None);
+        assert_eq!(m.diffuse_texture, expected.diffuse_texture);
+        assert_eq!(m.normal_texture, Some("".to_string()));
+    }
+
+    #[test]
+    fn stale_hash_is_ignored() {
+        let path = temp_dir("stale").join("cube.obj.cache");
+        write(&path, &test_mesh(), 42).unwrap();
+        assert!(read(&path, 43).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let path = temp_dir("truncated").join("cube.obj.cache");
+        write(&path, &test_mesh(), 42).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        for &len in &[2, 10, bytes.len() / 2, bytes.len() - 1] {
+            fs::write(&path, &bytes[..len]).unwrap();
+            assert!(read(&path, 42).is_err(), "read {} of {} bytes", len, bytes.len());
+        }
+    }
+
+    #[test]
+    fn other_version_is_rejected() {
+        let path = temp_dir("version").join("cube.obj.cache");
+        write(&path, &test_mesh(), 42).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert!(read(&path, 42).unwrap().is_none());
+    }
+
+    #[test]
+    fn hash_covers_material_library() {
+        let dir = temp_dir("mtl");
+        let obj = dir.join("model.obj");
+        fs::write(&obj, "mtllib model.mtl\nv 0 0 0\n").unwrap();
+        let obj = obj.to_str().unwrap();
+
+        let missing = hash_model(obj).unwrap();
+        fs::write(dir.join("model.mtl"), "newmtl a\nKd 1 0 0\n").unwrap();
+        let red = hash_model(obj).unwrap();
+        fs::write(dir.join("model.mtl"), "newmtl a\nKd 0 1 0\n").unwrap();
+        let green = hash_model(obj).unwrap();
+
+        assert_ne!(missing, red);
+        assert_ne!(red, green);
+        assert_eq!(green, hash_model(obj).unwrap());
+    }
+}
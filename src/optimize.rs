@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::mesh::Mesh;
+
+// Post-processing passes that make a mesh cheaper to draw without changing how it looks:
+// welding duplicated vertices, ordering the triangles so the GPU's post-transform vertex cache
+// gets more hits, and ordering the vertices in the order they're used, so they're fetched from
+// memory sequentially.
+
+// The cache size to optimize for. Actual hardware varies, but 32 is a good middle ground.
+const CACHE_SIZE: usize = 32;
+
+// How the vertex cache optimization scores vertices, from Tom Forsyth's "Linear-Speed Vertex
+// Cache Optimisation"
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRI_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+
+pub struct OptimizeStats {
+    pub vertices_before : usize,
+    pub vertices_after  : usize,
+    pub acmr_before     : f32,
+    pub acmr_after      : f32,
+}
+
+impl fmt::Display for OptimizeStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} -> {} vertices, ACMR {:.3} -> {:.3}",
+            self.vertices_before, self.vertices_after, self.acmr_before, self.acmr_after)
+    }
+}
+
+// Average cache miss ratio: the number of vertices transformed per triangle when drawing with
+// a FIFO vertex cache of the given size. 3 is the worst case, around 0.6 is very good.
+pub fn acmr(indices: &[u32], cache_size: usize) -> f32 {
+    if indices.len() < 3 {
+        return 0.0;
+    }
+    let mut cache: Vec<u32> = Vec::with_capacity(cache_size);
+    let mut misses = 0;
+    for &i in indices {
+        if !cache.contains(&i) {
+            misses += 1;
+            if cache.len() == cache_size {
+                cache.remove(0);
+            }
+            cache.push(i);
+        }
+    }
+    misses as f32 / (indices.len() / 3) as f32
+}
+
+// Merges vertices whose attributes all round to the same multiple of `tolerance`, returning how
+// many vertices were removed. This is a grid, not a distance check: values closer than `tolerance`
+// can still land on either side of a rounding boundary and stay apart, so the tolerance should be
+// well above the noise it is meant to absorb. A tolerance of 0 only merges exact duplicates.
+pub fn weld_vertices(mesh: &mut Mesh, tolerance: f32) -> usize {
+    let num_verts = mesh.vertices.len() / 3;
+    // Adding 0 turns -0 into +0, which would otherwise get a key of its own
+    let quantize = |x: f32| if tolerance > 0.0 { ((x / tolerance).round() + 0.0).to_bits() } else { (x + 0.0).to_bits() };
+    let attributes: [(&Vec<f32>, usize); 6] = [
+        (&mesh.vertices, 3), (&mesh.normals, 3), (&mesh.colors, 4),
+        (&mesh.uvs, 2), (&mesh.tangents, 4), (&mesh.bitangents, 3),
+    ];
+
+    let mut unique: HashMap<Vec<u32>, u32> = HashMap::new();
+    let mut sources = vec![];
+    let mut remap = Vec::with_capacity(num_verts);
+    for v in 0..num_verts {
+        let key: Vec<u32> = attributes.iter()
+            .filter(|(data, _)| !data.is_empty())
+            .flat_map(|&(data, n)| data[v * n..(v + 1) * n].iter().map(|&x| quantize(x)))
+            .collect();
+        remap.push(*unique.entry(key).or_insert_with(|| {
+            sources.push(v as u32);
+            sources.len() as u32 - 1
+        }));
+    }
+
+    for i in mesh.indices.iter_mut() {
+        *i = remap[*i as usize];
+    }
+    mesh.remap_vertices(&sources);
+    num_verts - sources.len()
+}
+
+// internal helper, the score of a vertex given its position in the cache (if any) and how many
+// triangles that haven't been emitted yet use it
+fn vertex_score(cache_position: Option<usize>, remaining: u32) -> f32 {
+    if remaining == 0 {
+        return -1.0;
+    }
+    let cache_score = match cache_position {
+        None => 0.0,
+        // The vertices of the last triangle get a fixed score, so the next triangle doesn't
+        // favour reusing an edge of the last triangle over the rest of the cache
+        Some(p) if p < 3 => LAST_TRI_SCORE,
+        Some(p) => (1.0 - (p - 3) as f32 / (CACHE_SIZE - 3) as f32).powf(CACHE_DECAY_POWER),
+    };
+    // Vertices with few triangles left get a boost, so they're finished off and leave the cache
+    cache_score + VALENCE_BOOST_SCALE * (remaining as f32).powf(-VALENCE_BOOST_POWER)
+}
+
+// internal helper, reorders one run of triangles for the vertex cache
+fn optimize_triangle_order(indices: &mut [u32], num_verts: usize) {
+    let num_tris = indices.len() / 3;
+    if num_tris == 0 {
+        return;
+    }
+
+    let mut vertex_tris: Vec<Vec<usize>> = vec![vec![]; num_verts];
+    for (t, tri) in indices.chunks(3).enumerate() {
+        for &v in tri {
+            vertex_tris[v as usize].push(t);
+        }
+    }
+    let mut remaining: Vec<u32> = vertex_tris.iter().map(|t| t.len() as u32).collect();
+    let mut cache_position: Vec<Option<usize>> = vec![None; num_verts];
+    let mut scores: Vec<f32> = remaining.iter().map(|&r| vertex_score(None, r)).collect();
+    let tri_score = |t: usize, scores: &[f32]| -> f32 {
+        indices[t * 3..t * 3 + 3].iter().map(|&v| scores[v as usize]).sum()
+    };
+
+    let mut emitted = vec![false; num_tris];
+    let mut order = Vec::with_capacity(num_tris);
+    let mut cache: Vec<u32> = Vec::with_capacity(CACHE_SIZE + 3);
+    let mut next_unemitted = 0;
+    let mut best: Option<usize> = None;
+
+    while order.len() < num_tris {
+        // Only the triangles around the cached vertices change score, so those are the only
+        // candidates. If none are left, continue from the first triangle not yet emitted.
+        let t = match best {
+            Some(t) => t,
+            None => {
+                while emitted[next_unemitted] {
+                    next_unemitted += 1;
+                }
+                next_unemitted
+            },
+        };
+        emitted[t] = true;
+        order.push(t);
+
+        let tri = [indices[t * 3], indices[t * 3 + 1], indices[t * 3 + 2]];
+        for &v in &tri {
+            remaining[v as usize] -= 1;
+            vertex_tris[v as usize].retain(|&other| other != t);
+        }
+
+        // Move the triangle's vertices to the front of the cache, pushing the oldest ones out
+        cache.retain(|v| !tri.contains(v));
+        let mut new_cache = tri.to_vec();
+        new_cache.extend(cache.iter());
+        for &v in new_cache.iter().skip(CACHE_SIZE) {
+            cache_position[v as usize] = None;
+            scores[v as usize] = vertex_score(None, remaining[v as usize]);
+        }
+        new_cache.truncate(CACHE_SIZE);
+        cache = new_cache;
+
+        best = None;
+        let mut best_score = f32::MIN;
+        for (p, &v) in cache.iter().enumerate() {
+            cache_position[v as usize] = Some(p);
+            scores[v as usize] = vertex_score(Some(p), remaining[v as usize]);
+        }
+        for &v in &cache {
+            for &other in &vertex_tris[v as usize] {
+                let score = tri_score(other, &scores);
+                if score > best_score {
+                    best_score = score;
+                    best = Some(other);
+                }
+            }
+        }
+    }
+
+    let reordered: Vec<u32> = order.iter().flat_map(|&t| indices[t * 3..t * 3 + 3].to_vec()).collect();
+    indices.copy_from_slice(&reordered);
+}
+
+// Reorders the triangles of the mesh to make better use of the vertex cache. Triangles are only
+// moved within their own submesh, so the submesh ranges stay valid.
+pub fn optimize_vertex_cache(mesh: &mut Mesh) {
+    let num_verts = mesh.vertices.len() / 3;
+    let mut ranges: Vec<(usize, usize)> = mesh.submeshes.iter()
+        .map(|s| (s.first_index, s.first_index + s.index_count as usize))
+        .collect();
+    if ranges.is_empty() {
+        ranges.push((0, mesh.indices.len()));
+    }
+    for (start, end) in ranges {
+        optimize_triangle_order(&mut mesh.indices[start..end], num_verts);
+    }
+}
+
+// Renumbers the vertices in the order the index buffer first uses them, dropping unused ones
+pub fn optimize_vertex_fetch(mesh: &mut Mesh) {
+    let mut new_index = vec![u32::MAX; mesh.vertices.len() / 3];
+    let mut sources = vec![];
+    for i in mesh.indices.iter_mut() {
+        if new_index[*i as usize] == u32::MAX {
+            new_index[*i as usize] = sources.len() as u32;
+            sources.push(*i);
+        }
+        *i = new_index[*i as usize];
+    }
+    mesh.remap_vertices(&sources);
+}
+
+// Runs all the passes above
+pub fn optimize(mesh: &mut Mesh, weld_tolerance: f32) -> OptimizeStats {
+    let vertices_before = mesh.vertices.len() / 3;
+    let acmr_before = acmr(&mesh.indices, CACHE_SIZE);
+
+    weld_vertices(mesh, weld_tolerance);
+    optimize_vertex_cache(mesh);
+    optimize_vertex_fetch(mesh);
+
+    OptimizeStats {
+        vertices_before,
+        vertices_after: mesh.vertices.len() / 3,
+        acmr_before,
+        acmr_after: acmr(&mesh.indices, CACHE_SIZE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives;
+
+    // A grid where every triangle has vertices of its own, like a model exported without sharing
+    fn unwelded_grid(segments: u32) -> Mesh {
+        let mut mesh = primitives::plane(10.0, 10.0, segments, segments, [1.0, 1.0, 1.0, 1.0]);
+        let sources = mesh.indices.clone();
+        mesh.remap_vertices(&sources);
+        mesh.indices = (0..sources.len() as u32).collect();
+        mesh
+    }
+
+    // The triangles by the positions of their corners, each starting from its smallest corner so
+    // the winding counts but the first corner doesn't, sorted so the order doesn't either
+    fn triangles(mesh: &Mesh) -> Vec<[[u32; 3]; 3]> {
+        let corner = |i: u32| {
+            let p = mesh.position(i);
+            [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]
+        };
+        let mut tris: Vec<[[u32; 3]; 3]> = mesh.indices.chunks(3)
+            .map(|t| {
+                let mut tri = [corner(t[0]), corner(t[1]), corner(t[2])];
+                let first = (0..3).min_by_key(|&i| tri[i]).unwrap();
+                tri.rotate_left(first);
+                tri
+            })
+            .collect();
+        tris.sort();
+        tris
+    }
+
+    fn triangle_mesh(vertices: Vec<f32>) -> Mesh {
+        let indices = (0..vertices.len() as u32 / 3).collect();
+        Mesh::new(vertices, vec![], vec![], indices, [1.0, 1.0, 1.0, 1.0])
+    }
+
+    #[test]
+    fn welds_across_signed_zero() {
+        let mut mesh = triangle_mesh(vec![
+            -1e-7, 0.0, 0.0,   1.0, 0.0, 0.0,   0.0, 1.0, 0.0,
+             1e-7, 0.0, 0.0,   1.0, 0.0, 0.0,   0.0, 1.0, 0.0,
+        ]);
+        assert_eq!(weld_vertices(&mut mesh, 1e-5), 3);
+        assert_eq!(mesh.vertices.len(), 9);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn exact_weld_treats_negative_zero_as_zero() {
+        let mut mesh = triangle_mesh(vec![
+            -0.0, 0.0, 0.0,   1.0, 0.0, 0.0,   0.0, 1.0, 0.0,
+             0.0, 0.0, 0.0,   1.0, 0.0, 0.0,   0.0, 1.0, 0.0,
+        ]);
+        assert_eq!(weld_vertices(&mut mesh, 0.0), 3);
+    }
+
+    #[test]
+    fn weld_is_grid_quantization() {
+        let pair = |a: f32, b: f32| triangle_mesh(vec![
+            a, 0.0, 0.0,   1.0, 0.0, 0.0,   0.0, 1.0, 0.0,
+            b, 0.0, 0.0,   1.0, 0.0, 0.0,   0.0, 1.0, 0.0,
+        ]);
+        // In the same cell, although almost a whole tolerance apart
+        assert_eq!(weld_vertices(&mut pair(0.51e-5, 1.49e-5), 1e-5), 3);
+        // On either side of a rounding boundary, although much closer
+        assert_eq!(weld_vertices(&mut pair(0.49e-5, 0.51e-5), 1e-5), 2);
+    }
+
+    #[test]
+    fn weld_keeps_distinct_vertices() {
+        let mut mesh = triangle_mesh(vec![
+            0.0, 0.0, 0.0,   1.0, 0.0, 0.0,   0.0, 1.0, 0.0,
+            0.1, 0.0, 0.0,   1.0, 0.0, 0.0,   0.0, 1.0, 0.0,
+        ]);
+        assert_eq!(weld_vertices(&mut mesh, 1e-5), 2);
+    }
+
+    #[test]
+    fn optimize_grid() {
+        let mut mesh = unwelded_grid(16);
+        let before = triangles(&mesh);
+        let stats = optimize(&mut mesh, 1e-5);
+
+        assert_eq!(stats.vertices_before, 16 * 16 * 6);
+        assert_eq!(stats.vertices_after, 17 * 17);
+        assert!((stats.acmr_before - 3.0).abs() < 1e-6);
+        assert!(stats.acmr_after < 1.0, "ACMR only went down to {}", stats.acmr_after);
+        assert_eq!(triangles(&mesh), before);
+    }
+
+    #[test]
+    fn vertex_fetch_order_follows_indices() {
+        let mut mesh = primitives::plane(1.0, 1.0, 4, 4, [1.0, 1.0, 1.0, 1.0]);
+        mesh.indices.reverse();
+        let before = triangles(&mesh);
+        optimize_vertex_fetch(&mut mesh);
+        let mut next = 0;
+        for &i in &mesh.indices {
+            assert!(i <= next);
+            if i == next {
+                next += 1;
+            }
+        }
+        assert_eq!(triangles(&mesh), before);
+    }
+}
@@ -0,0 +1,296 @@
+extern crate nalgebra_glm as glm;
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use crate::mesh::Mesh;
+
+// Generators for simple shapes, handy for test scenes, debug markers and placeholders.
+// Every shape is centered on the origin with Y up, has counter-clockwise front faces, and comes
+// with normals and texture coordinates.
+
+// internal helper, collects vertices and triangles before turning them into a Mesh
+struct Builder {
+    vertices : Vec<f32>,
+    normals  : Vec<f32>,
+    uvs      : Vec<f32>,
+    indices  : Vec<u32>,
+}
+
+impl Builder {
+    fn new() -> Self {
+        Builder { vertices: vec![], normals: vec![], uvs: vec![], indices: vec![] }
+    }
+
+    fn vertex(&mut self, p: glm::Vec3, n: glm::Vec3, u: f32, v: f32) -> u32 {
+        self.vertices.extend_from_slice(&[p.x, p.y, p.z]);
+        self.normals.extend_from_slice(&[n.x, n.y, n.z]);
+        self.uvs.extend_from_slice(&[u, v]);
+        (self.vertices.len() / 3 - 1) as u32
+    }
+
+    fn triangle(&mut self, a: u32, b: u32, c: u32) {
+        self.indices.extend_from_slice(&[a, b, c]);
+    }
+
+    // Two triangles, with the corners given counter-clockwise
+    fn quad(&mut self, a: u32, b: u32, c: u32, d: u32) {
+        self.triangle(a, b, c);
+        self.triangle(a, c, d);
+    }
+
+    fn build(self, color: [f32; 4]) -> Mesh {
+        Mesh::new(self.vertices, self.normals, self.uvs, self.indices, color)
+    }
+}
+
+// The point on the unit circle in the XZ plane at angle `phi`, going from +Z towards +X
+fn around_y(phi: f32) -> glm::Vec3 {
+    glm::vec3(phi.sin(), 0.0, phi.cos())
+}
+
+// internal helper for the sphere and capsule. Each row is a ring of vertices at polar angle
+// theta, shifted `y_offset` along Y and mapped to texture row `v`. Rows go from top to bottom.
+fn lathe_sphere(b: &mut Builder, rows: &[(f32, f32, f32)], radius: f32, segments: u32) {
+    let first = b.vertices.len() as u32 / 3;
+    for &(theta, y_offset, v) in rows {
+        for s in 0..=segments {
+            let u = s as f32 / segments as f32;
+            let n = around_y(u * 2.0 * PI) * theta.sin() + glm::vec3(0.0, theta.cos(), 0.0);
+            b.vertex(n * radius + glm::vec3(0.0, y_offset, 0.0), n, u, v);
+        }
+    }
+
+    let stride = segments + 1;
+    let last_row = rows.len() as u32 - 1;
+    for r in 0..last_row {
+        for s in 0..segments {
+            let a = first + r * stride + s;
+            let (below, below_next, next) = (a + stride, a + stride + 1, a + 1);
+            // The triangles touching the poles would be degenerate
+            if r != last_row - 1 || rows[rows.len() - 1].0 < PI {
+                b.triangle(a, below, below_next);
+            }
+            if r != 0 || rows[0].0 > 0.0 {
+                b.triangle(a, below_next, next);
+            }
+        }
+    }
+}
+
+// A cube with side length `size`. Each face has its own vertices so the edges stay sharp, and
+// shows the whole texture.
+#[allow(dead_code)]
+pub fn cube(size: f32, color: [f32; 4]) -> Mesh {
+    let mut b = Builder::new();
+    let h = size / 2.0;
+    let (x, y, z) = (glm::vec3(1.0, 0.0, 0.0), glm::vec3(0.0, 1.0, 0.0), glm::vec3(0.0, 0.0, 1.0));
+    // The normal of each face, and two axes along it such that cross(u, v) = normal
+    for &(n, u, v) in &[(x, -z, y), (-x, z, y), (y, x, -z), (-y, x, z), (z, x, y), (-z, -x, y)] {
+        let corner = |su: f32, sv: f32| (n + u * su + v * sv) * h;
+        let a = b.vertex(corner(-1.0, -1.0), n, 0.0, 0.0);
+        let c = b.vertex(corner( 1.0, -1.0), n, 1.0, 0.0);
+        let d = b.vertex(corner( 1.0,  1.0), n, 1.0, 1.0);
+        let e = b.vertex(corner(-1.0,  1.0), n, 0.0, 1.0);
+        b.quad(a, c, d, e);
+    }
+    b.build(color)
+}
+
+// A sphere made of `segments` slices around Y and `rings` stacks from pole to pole. The texture
+// is wrapped around it once, with the seam at +Z.
+#[allow(dead_code)]
+pub fn uv_sphere(radius: f32, segments: u32, rings: u32, color: [f32; 4]) -> Mesh {
+    let (segments, rings) = (segments.max(3), rings.max(2));
+    let rows: Vec<(f32, f32, f32)> = (0..=rings)
+        .map(|r| (r as f32 / rings as f32 * PI, 0.0, 1.0 - r as f32 / rings as f32))
+        .collect();
+    let mut b = Builder::new();
+    lathe_sphere(&mut b, &rows, radius, segments);
+    b.build(color)
+}
+
+// A sphere made by splitting each triangle of an icosahedron into four `subdivisions` times,
+// which gives evenly sized triangles unlike the UV sphere
+#[allow(dead_code)]
+pub fn icosphere(radius: f32, subdivisions: u32, color: [f32; 4]) -> Mesh {
+    let t = (1.0 + 5f32.sqrt()) / 2.0;
+    let mut points: Vec<glm::Vec3> = [
+        (-1.0, t, 0.0), (1.0, t, 0.0), (-1.0, -t, 0.0), (1.0, -t, 0.0),
+        (0.0, -1.0, t), (0.0, 1.0, t), (0.0, -1.0, -t), (0.0, 1.0, -t),
+        (t, 0.0, -1.0), (t, 0.0, 1.0), (-t, 0.0, -1.0), (-t, 0.0, 1.0),
+    ].iter().map(|&(x, y, z)| glm::normalize(&glm::vec3(x, y, z))).collect();
+    let mut faces: Vec<[u32; 3]> = vec![
+        [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+        [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+        [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+        [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+    ];
+
+    for _ in 0..subdivisions {
+        // Edges are shared by two faces, so remember the midpoints we've already made
+        let mut midpoints: HashMap<(u32, u32), u32> = HashMap::new();
+        let mut midpoint = |a: u32, b: u32, points: &mut Vec<glm::Vec3>| {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                points.push(glm::normalize(&(points[a as usize] + points[b as usize])));
+                points.len() as u32 - 1
+            })
+        };
+        faces = faces.iter().flat_map(|&[a, b, c]| {
+            let ab = midpoint(a, b, &mut points);
+            let bc = midpoint(b, c, &mut points);
+            let ca = midpoint(c, a, &mut points);
+            vec![[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+        }).collect();
+    }
+
+    // Spherical texture mapping. Triangles crossing the seam get their own copies of the
+    // vertices on the far side, shifted a whole turn, so the texture doesn't smear across them.
+    let mut b = Builder::new();
+    let uv = |p: &glm::Vec3| (0.5 + p.x.atan2(p.z) / (2.0 * PI), 0.5 + p.y.asin() / PI);
+    for p in &points {
+        let (u, v) = uv(p);
+        b.vertex(p * radius, *p, u, v);
+    }
+    let mut wrapped: HashMap<u32, u32> = HashMap::new();
+    for face in faces {
+        let us: Vec<f32> = face.iter().map(|&i| uv(&points[i as usize]).0).collect();
+        let crosses_seam = us.iter().cloned().fold(f32::MIN, f32::max) - us.iter().cloned().fold(f32::MAX, f32::min) > 0.5;
+        let corners: Vec<u32> = face.iter().zip(&us).map(|(&i, &u)| {
+            if crosses_seam && u < 0.5 {
+                *wrapped.entry(i).or_insert_with(|| {
+                    let p = points[i as usize];
+                    b.vertex(p * radius, p, u + 1.0, uv(&p).1)
+                })
+            } else {
+                i
+            }
+        }).collect();
+        b.triangle(corners[0], corners[1], corners[2]);
+    }
+    b.build(color)
+}
+
+// internal helper for the cylinder and cone, a tube that narrows from `bottom_radius` to
+// `top_radius`, closed off by flat caps
+fn frustum(bottom_radius: f32, top_radius: f32, height: f32, segments: u32, color: [f32; 4]) -> Mesh {
+    let segments = segments.max(3);
+    let mut b = Builder::new();
+    let (top, bottom) = (height / 2.0, -height / 2.0);
+
+    // The side, with normals tilted to follow the slope
+    for s in 0..=segments {
+        let u = s as f32 / segments as f32;
+        let dir = around_y(u * 2.0 * PI);
+        let n = glm::normalize(&(dir * height + glm::vec3(0.0, bottom_radius - top_radius, 0.0)));
+        b.vertex(dir * top_radius + glm::vec3(0.0, top, 0.0), n, u, 1.0);
+        b.vertex(dir * bottom_radius + glm::vec3(0.0, bottom, 0.0), n, u, 0.0);
+    }
+    for s in 0..segments {
+        let (a, below, below_next, next) = (s * 2, s * 2 + 1, s * 2 + 3, s * 2 + 2);
+        b.triangle(a, below, below_next);
+        if top_radius > 0.0 {
+            b.triangle(a, below_next, next);
+        }
+    }
+
+    // The caps, textured as if the texture was projected straight down on them
+    for &(y, radius, up) in &[(top, top_radius, 1.0), (bottom, bottom_radius, -1.0)] {
+        if radius <= 0.0 {
+            continue;
+        }
+        let n = glm::vec3(0.0, up, 0.0);
+        let center = b.vertex(glm::vec3(0.0, y, 0.0), n, 0.5, 0.5);
+        for s in 0..=segments {
+            let dir = around_y(s as f32 / segments as f32 * 2.0 * PI);
+            b.vertex(dir * radius + glm::vec3(0.0, y, 0.0), n, 0.5 + dir.x / 2.0, 0.5 - dir.z / 2.0);
+            if s > 0 {
+                let (prev, cur) = (center + s, center + s + 1);
+                if up > 0.0 { b.triangle(center, prev, cur) } else { b.triangle(center, cur, prev) }
+            }
+        }
+    }
+    b.build(color)
+}
+
+// A cylinder along Y, `height` tall and capped at both ends
+#[allow(dead_code)]
+pub fn cylinder(radius: f32, height: f32, segments: u32, color: [f32; 4]) -> Mesh {
+    frustum(radius, radius, height, segments, color)
+}
+
+// A cone along Y with its base at the bottom and tip at the top
+#[allow(dead_code)]
+pub fn cone(radius: f32, height: f32, segments: u32, color: [f32; 4]) -> Mesh {
+    frustum(radius, 0.0, height, segments, color)
+}
+
+// A torus lying in the XZ plane. `major_radius` is the distance from the center to the middle of
+// the tube, `minor_radius` is the radius of the tube itself.
+#[allow(dead_code)]
+pub fn torus(major_radius: f32, minor_radius: f32, major_segments: u32, minor_segments: u32, color: [f32; 4]) -> Mesh {
+    let (major_segments, minor_segments) = (major_segments.max(3), minor_segments.max(3));
+    let mut b = Builder::new();
+    for i in 0..=major_segments {
+        let u = i as f32 / major_segments as f32;
+        let dir = around_y(u * 2.0 * PI);
+        for j in 0..=minor_segments {
+            let v = j as f32 / minor_segments as f32;
+            let theta = v * 2.0 * PI;
+            let n = dir * theta.cos() + glm::vec3(0.0, theta.sin(), 0.0);
+            b.vertex(dir * major_radius + n * minor_radius, n, u, v);
+        }
+    }
+    let stride = minor_segments + 1;
+    for i in 0..major_segments {
+        for j in 0..minor_segments {
+            let a = i * stride + j;
+            b.quad(a, a + stride, a + stride + 1, a + 1);
+        }
+    }
+    b.build(color)
+}
+
+// A flat grid in the XZ plane facing up, `width` along X and `depth` along Z
+#[allow(dead_code)]
+pub fn plane(width: f32, depth: f32, x_segments: u32, z_segments: u32, color: [f32; 4]) -> Mesh {
+    let (x_segments, z_segments) = (x_segments.max(1), z_segments.max(1));
+    let mut b = Builder::new();
+    let up = glm::vec3(0.0, 1.0, 0.0);
+    for z in 0..=z_segments {
+        let v = z as f32 / z_segments as f32;
+        for x in 0..=x_segments {
+            let u = x as f32 / x_segments as f32;
+            b.vertex(glm::vec3((u - 0.5) * width, 0.0, (v - 0.5) * depth), up, u, v);
+        }
+    }
+    let stride = x_segments + 1;
+    for z in 0..z_segments {
+        for x in 0..x_segments {
+            let a = z * stride + x;
+            b.quad(a, a + stride, a + stride + 1, a + 1);
+        }
+    }
+    b.build(color)
+}
+
+// A cylinder along Y with hemispheres on both ends. `height` is the length of the straight part,
+// so the whole capsule is `height + 2 * radius` tall. Each hemisphere has `rings` stacks.
+#[allow(dead_code)]
+pub fn capsule(radius: f32, height: f32, segments: u32, rings: u32, color: [f32; 4]) -> Mesh {
+    let (segments, rings) = (segments.max(3), rings.max(1));
+    // The texture runs along the length of the capsule, in proportion to the distance covered
+    let total = PI * radius + height;
+    let mut rows = vec![];
+    for r in 0..=rings {
+        let theta = r as f32 / rings as f32 * PI / 2.0;
+        rows.push((theta, height / 2.0, 1.0 - theta * radius / total));
+    }
+    for r in 0..=rings {
+        let theta = PI / 2.0 + r as f32 / rings as f32 * PI / 2.0;
+        rows.push((theta, -height / 2.0, 1.0 - (theta * radius + height) / total));
+    }
+    let mut b = Builder::new();
+    lathe_sphere(&mut b, &rows, radius, segments);
+    b.build(color)
+}
@@ -0,0 +1,505 @@
+extern crate nalgebra_glm as glm;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::gltf_loader::{self, GltfFile, GltfMesh};
+use crate::gpu_mesh::GpuMesh;
+use crate::mesh::{Mesh, MeshError, Model, Terrain};
+use crate::primitives;
+use crate::scene_graph::{quat_from_euler, Animation, EulerOrder, Instance, Lod, NodeId, SceneGraph, SceneNode};
+use crate::simplify;
+use crate::texture::Texture;
+use crate::vertex_layout::VertexLayout;
+
+// A scene described in a RON file: the meshes it uses and the nodes drawing them. Everything but
+// the names of meshes and nodes can be left out and gets the same default as SceneNode, e.g.
+//
+//     Scene(
+//         meshes: [
+//             (name: "body", source: Part(path: "./resources/helicopter.obj", part: "Body_body", color: (0.3, 0.3, 0.3, 1.0))),
+//             (name: "tail_rotor", source: Part(path: "./resources/helicopter.obj", part: "Tail_Rotor_tail_rotor", color: (0.1, 0.3, 0.1, 1.0))),
+//         ],
+//         prototypes: [
+//             (name: Some("helicopter"), mesh: Some("body"), children: [
+//                 (name: Some("tail_rotor"), mesh: Some("tail_rotor"), reference_point: (0.35, 2.3, 10.4),
+//                     animation: Some(Spin(axis: (1.0, 0.0, 0.0), speed: 10.0))),
+//             ]),
+//         ],
+//         nodes: [
+//             (name: Some("helicopter"), animation: Some(Heading), instance: Some((prototype: "helicopter"))),
+//         ],
+//     )
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename = "Scene")]
+pub struct SceneDescription {
+    pub meshes: Vec<MeshDescription>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prototypes: Vec<NodeDescription>, // Drawn only through instances, see SceneGraph
+    pub nodes: Vec<NodeDescription>,      // The roots of the scene
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MeshDescription {
+    pub name: String,                     // What nodes refer to the mesh by
+    pub source: MeshSource,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub texture: Option<String>,          // Overrides the texture of the mesh's material
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal_map: Option<String>,       // Overrides the normal map of the mesh's material
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lods: Vec<LodDescription>,        // Simplified versions to make, finest first
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum MeshSource {
+    // A whole OBJ file merged into one mesh, loaded through the terrain cache
+    Terrain { path: String },
+    // One object of an OBJ file, painted `color` if it has no material
+    Part { path: String, part: String, color: (f32, f32, f32, f32) },
+    // One mesh of a .gltf or .glb file, unnamed meshes being "mesh_" and their index. Its images
+    // are used unless the description names textures of its own, and primitives without a
+    // material are white, like the glTF default material.
+    Gltf { path: String, mesh: String },
+    // A cube from primitives, e.g. as a stand-in for a model that failed to load
+    Cube { size: f32, color: (f32, f32, f32, f32) },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct LodDescription {
+    pub ratio: f32,                       // How many of the triangles to keep
+    pub screen_size: f32,                 // See scene_graph::Lod
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NodeDescription {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mesh: Option<String>,
+    #[serde(default = "zero", skip_serializing_if = "is_zero")]
+    pub position: (f32, f32, f32),
+    #[serde(default, skip_serializing_if = "is_identity")]
+    pub orientation: Orientation,
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub scale: (f32, f32, f32),
+    #[serde(default = "zero", skip_serializing_if = "is_zero")]
+    pub reference_point: (f32, f32, f32),
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub animation: Option<AnimationDescription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instance: Option<InstanceDescription>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<NodeDescription>,
+}
+
+impl Default for NodeDescription {
+    fn default() -> NodeDescription {
+        NodeDescription {
+            name: None,
+            mesh: None,
+            position: zero(),
+            orientation: Orientation::default(),
+            scale: one(),
+            reference_point: zero(),
+            animation: None,
+            instance: None,
+            children: vec![],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Orientation {
+    // A unit quaternion, given as (x, y, z, w)
+    Quat(f32, f32, f32, f32),
+    // Angles in radians about X, Y and Z, applied like a heading: rolling about Z first, then
+    // pitching about X and turning about Y last
+    Euler(f32, f32, f32),
+}
+
+impl Default for Orientation {
+    fn default() -> Orientation {
+        Orientation::Quat(0.0, 0.0, 0.0, 1.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum AnimationDescription {
+    Spin { axis: (f32, f32, f32), speed: f32 },
+    Heading,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InstanceDescription {
+    pub prototype: String,                // The name of one of the prototypes
+    #[serde(default, skip_serializing_if = "is_zero_f32")]
+    pub time_offset: f32,
+}
+
+// internal helpers for the defaults of the description
+fn zero() -> (f32, f32, f32) { (0.0, 0.0, 0.0) }
+fn one() -> (f32, f32, f32) { (1.0, 1.0, 1.0) }
+fn is_zero(v: &(f32, f32, f32)) -> bool { *v == zero() }
+fn is_one(v: &(f32, f32, f32)) -> bool { *v == one() }
+fn is_zero_f32(v: &f32) -> bool { *v == 0.0 }
+fn is_identity(o: &Orientation) -> bool { *o == Orientation::default() }
+
+fn vec3(v: (f32, f32, f32)) -> glm::Vec3 { glm::vec3(v.0, v.1, v.2) }
+fn tuple3(v: &glm::Vec3) -> (f32, f32, f32) { (v.x, v.y, v.z) }
+
+// Everything that can go wrong when loading or saving a scene
+#[derive(Debug)]
+pub enum SceneError {
+    Io { path: String, error: std::io::Error },
+    Parse { path: String, error: ron::Error },
+    Write { path: String, error: ron::Error },
+    Mesh(MeshError),
+    UnknownMesh { node: String, mesh: String },
+    UnknownPrototype { node: String, prototype: String },
+    UnnamedPrototype,
+    MissingPrototype { node: String },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io { path, error }
+                => write!(f, "Failed to access {}: {}", path, error),
+            SceneError::Parse { path, error }
+                => write!(f, "Failed to parse {}: {}", path, error),
+            SceneError::Write { path, error }
+                => write!(f, "Failed to write {}: {}", path, error),
+            SceneError::Mesh(error)
+                => write!(f, "{}", error),
+            SceneError::UnknownMesh { node, mesh }
+                => write!(f, "Node {} uses the mesh {}, which isn't in the scene", node, mesh),
+            SceneError::UnknownPrototype { node, prototype }
+                => write!(f, "Node {} is an instance of {}, which isn't a prototype in the scene", node, prototype),
+            SceneError::UnnamedPrototype
+                => write!(f, "Prototypes must be named to be saved, as instances refer to them by name"),
+            SceneError::MissingPrototype { node }
+                => write!(f, "Node {} is an instance of a prototype that has been removed", node),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<MeshError> for SceneError {
+    fn from(error: MeshError) -> SceneError {
+        SceneError::Mesh(error)
+    }
+}
+
+// A mesh of the scene, uploaded along with its levels of detail. The textures are owned by the
+// scene, as meshes can share them.
+struct SceneMesh {
+    description   : MeshDescription,
+    gpu           : GpuMesh,
+    lods          : Vec<GpuMesh>,
+    bounds        : crate::mesh::Bounds,
+    texture_id    : u32,
+    normal_map_id : u32,
+}
+
+// A scene graph built from a scene description, owning the meshes and textures its nodes draw.
+// The graph can be changed freely and saved again, meshes are written out by name for the nodes
+// still drawing them.
+pub struct Scene {
+    pub graph : SceneGraph,
+    meshes    : Vec<SceneMesh>,
+    #[allow(dead_code)]
+    textures  : Vec<Texture>,       // Never read, only kept for the nodes drawing with their ids
+}
+
+// internal helper, the texture named by the description, or else by the material, which names it
+// relative to the model
+fn texture_path(explicit: &Option<String>, from_material: Option<&String>, model_path: &str) -> Option<String> {
+    explicit.clone().or_else(|| from_material.map(|t| {
+        Path::new(model_path).with_file_name(t).to_string_lossy().into_owned()
+    }))
+}
+
+// internal helper, the textures uploaded while building a scene. The images of glTF files are
+// uploaded once, however many meshes use them.
+#[derive(Default)]
+struct Textures {
+    textures    : Vec<Texture>,
+    gltf_images : HashMap<(String, usize), u32>,   // Texture id by file and image index
+}
+
+impl Textures {
+    fn keep(&mut self, texture: Texture) -> u32 {
+        let id = texture.texture_id;
+        self.textures.push(texture);
+        id
+    }
+
+    // Textures that fail to load are left out, so the mesh is still drawn. glTF models expect
+    // their images the other way up from OBJ files, so those aren't flipped.
+    unsafe fn load(&mut self, path: Option<String>, what: &str, gltf: bool) -> u32 {
+        let loaded = path.map(|p| (if gltf { Texture::load_unflipped(&p) } else { Texture::load(&p) }, p));
+        match loaded {
+            Some((Ok(texture), _)) => self.keep(texture),
+            Some((Err(e), p)) => {
+                println!("No {} loaded from {}: {}", what, p, e);
+                0
+            },
+            None => 0,
+        }
+    }
+
+    unsafe fn gltf_image(&mut self, path: &str, file: &GltfFile, image: usize) -> u32 {
+        if let Some(&id) = self.gltf_images.get(&(path.to_string(), image)) {
+            return id;
+        }
+        let id = self.keep(gltf_loader::upload_image(&file.images[image]));
+        self.gltf_images.insert((path.to_string(), image), id);
+        id
+    }
+}
+
+impl Scene {
+    // Reads the scene description and loads every mesh in it, uploading them laid out as `layout`
+    pub unsafe fn load(path: &str, layout: &VertexLayout) -> Result<Scene, SceneError> {
+        let text = fs::read_to_string(path)
+            .map_err(|error| SceneError::Io { path: path.to_string(), error })?;
+        let description: SceneDescription = ron::from_str(&text)
+            .map_err(|error| SceneError::Parse { path: path.to_string(), error })?;
+        Scene::build(description, layout)
+    }
+
+    // A scene with nothing but a cube named "placeholder", for when the real one can't be loaded
+    pub unsafe fn placeholder(layout: &VertexLayout) -> Scene {
+        let description = SceneDescription {
+            meshes: vec![MeshDescription {
+                name: "placeholder".to_string(),
+                source: MeshSource::Cube { size: 10.0, color: (1.0, 0.0, 1.0, 1.0) },
+                texture: None,
+                normal_map: None,
+                lods: vec![],
+            }],
+            prototypes: vec![],
+            nodes: vec![NodeDescription {
+                name: Some("placeholder".to_string()),
+                mesh: Some("placeholder".to_string()),
+                ..NodeDescription::default()
+            }],
+        };
+        // Nothing in it is read from disk, so there is nothing to fail
+        Scene::build(description, layout).expect("Failed to build the placeholder scene")
+    }
+
+    // Loads the meshes of the description and builds its scene graph
+    pub unsafe fn build(description: SceneDescription, layout: &VertexLayout) -> Result<Scene, SceneError> {
+        // Every part of an OBJ is loaded in one go, as parsing the file is what takes time
+        let mut models: HashMap<&str, Model> = HashMap::new();
+        for m in &description.meshes {
+            if let MeshSource::Part { path, .. } = &m.source {
+                if !models.contains_key(path.as_str()) {
+                    let parts: Vec<(&str, [f32; 4])> = description.meshes.iter()
+                        .filter_map(|other| match &other.source {
+                            MeshSource::Part { path: p, part, color } if p == path
+                                => Some((part.as_str(), [color.0, color.1, color.2, color.3])),
+                            _ => None,
+                        })
+                        .collect();
+                    models.insert(path, Model::load(path, &parts, [1.0, 1.0, 1.0, 1.0])?);
+                }
+            }
+        }
+        // The same goes for glTF files
+        let mut gltf_files: HashMap<&str, GltfFile> = HashMap::new();
+        for m in &description.meshes {
+            if let MeshSource::Gltf { path, .. } = &m.source {
+                if !gltf_files.contains_key(path.as_str()) {
+                    gltf_files.insert(path, gltf_loader::read(path, [1.0, 1.0, 1.0, 1.0])?);
+                }
+            }
+        }
+
+        let mut meshes = Vec::with_capacity(description.meshes.len());
+        let mut textures = Textures::default();
+        for m in &description.meshes {
+            let mut gltf: Option<(&GltfFile, &GltfMesh)> = None;
+            let (mut mesh, model_path): (Mesh, &str) = match &m.source {
+                MeshSource::Terrain { path } => (Terrain::load(path)?, path),
+                MeshSource::Part { path, part, .. } => {
+                    let mesh = models[path.as_str()].get(part)
+                        .ok_or_else(|| MeshError::MissingParts { path: path.clone(), parts: vec![part.clone()] })?;
+                    (mesh.clone(), path)
+                },
+                MeshSource::Gltf { path, mesh } => {
+                    let file = &gltf_files[path.as_str()];
+                    let gltf_mesh = file.get(mesh)
+                        .ok_or_else(|| MeshError::MissingParts { path: path.clone(), parts: vec![mesh.clone()] })?;
+                    gltf = Some((file, gltf_mesh));
+                    (gltf_mesh.mesh.clone(), path)
+                },
+                MeshSource::Cube { size, color } => (primitives::cube(*size, [color.0, color.1, color.2, color.3]), ""),
+            };
+            // glTF images have been read already, and are laid out differently from image files,
+            // so only the textures the description names are loaded from disk for those
+            let material = if gltf.is_some() { None } else { mesh.material.clone() };
+            let texture = texture_path(&m.texture, material.as_ref().and_then(|m| m.diffuse_texture.as_ref()), model_path);
+            let normal_map = mesh.with_normal_map(texture_path(&m.normal_map, material.as_ref().and_then(|m| m.normal_texture.as_ref()), model_path));
+
+            let (texture_id, normal_map_id) = match gltf {
+                Some((file, gltf_mesh)) => (
+                    if texture.is_some() { textures.load(texture, "texture", true) }
+                    else { gltf_mesh.texture.map_or(0, |i| textures.gltf_image(model_path, file, i)) },
+                    if normal_map.is_some() { textures.load(normal_map, "normal map", true) }
+                    else { gltf_mesh.normal_map.map_or(0, |i| textures.gltf_image(model_path, file, i)) },
+                ),
+                None => (textures.load(texture, "texture", false), textures.load(normal_map, "normal map", false)),
+            };
+
+            let ratios: Vec<f32> = m.lods.iter().map(|lod| lod.ratio).collect();
+            let lods = simplify::lod_chain(&mesh, &ratios).iter()
+                .map(|lod| GpuMesh::upload(lod, layout))
+                .collect();
+
+            meshes.push(SceneMesh {
+                description: m.clone(),
+                gpu: GpuMesh::upload(&mesh, layout),
+                lods,
+                bounds: mesh.bounds,
+                texture_id,
+                normal_map_id,
+            });
+        }
+
+        let mut scene = Scene { graph: SceneGraph::new(), meshes, textures: textures.textures };
+
+        // Prototypes first, so instances can be pointed at them. A prototype can only use the ones
+        // listed before it, so they can't end up drawing each other in a loop.
+        let mut prototypes = HashMap::new();
+        for p in &description.prototypes {
+            let node = scene.node_from(p, &prototypes)?;
+            let id = scene.graph.add_prototype(node);
+            scene.add_children(id, p, &prototypes)?;
+            if let Some(name) = &p.name {
+                prototypes.insert(name.clone(), id);
+            }
+        }
+        for n in &description.nodes {
+            let node = scene.node_from(n, &prototypes)?;
+            let id = scene.graph.add(node);
+            scene.add_children(id, n, &prototypes)?;
+        }
+        Ok(scene)
+    }
+
+    // internal helper, adds the children of the description below `parent`
+    fn add_children(&mut self, parent: NodeId, description: &NodeDescription, prototypes: &HashMap<String, NodeId>) -> Result<(), SceneError> {
+        for c in &description.children {
+            let child = self.node_from(c, prototypes)?;
+            let child = self.graph.add(child);
+            self.graph.add_child(parent, child);
+            self.add_children(child, c, prototypes)?;
+        }
+        Ok(())
+    }
+
+    // internal helper, the node described, without its children
+    fn node_from(&self, description: &NodeDescription, prototypes: &HashMap<String, NodeId>) -> Result<SceneNode, SceneError> {
+        let node_name = || description.name.clone().unwrap_or_else(|| "?".to_string());
+        let mut node = SceneNode {
+            name: description.name.clone(),
+            position: vec3(description.position),
+            orientation: match description.orientation {
+                Orientation::Quat(x, y, z, w) => glm::quat_normalize(&glm::quat(x, y, z, w)),
+                Orientation::Euler(x, y, z) => quat_from_euler(&glm::vec3(x, y, z), EulerOrder::Zxy),
+            },
+            scale: vec3(description.scale),
+            reference_point: vec3(description.reference_point),
+            animation: description.animation.map(|a| match a {
+                AnimationDescription::Spin { axis, speed } => Animation::Spin { axis: vec3(axis), speed },
+                AnimationDescription::Heading => Animation::Heading,
+            }),
+            ..SceneNode::default()
+        };
+
+        if let Some(name) = &description.mesh {
+            let mesh = self.meshes.iter().find(|m| &m.description.name == name)
+                .ok_or_else(|| SceneError::UnknownMesh { node: node_name(), mesh: name.clone() })?;
+            node.vao_id = mesh.gpu.vao_id();
+            node.index_count = mesh.gpu.index_count();
+            node.primitive = mesh.gpu.primitive();
+            node.texture_id = mesh.texture_id;
+            node.normal_map_id = mesh.normal_map_id;
+            node.bounds = Some(mesh.bounds);
+            node.lods = mesh.lods.iter().zip(&mesh.description.lods)
+                .map(|(lod, d)| Lod { vao_id: lod.vao_id(), index_count: lod.index_count(), screen_size: d.screen_size })
+                .collect();
+        }
+
+        if let Some(instance) = &description.instance {
+            let prototype = *prototypes.get(&instance.prototype)
+                .ok_or_else(|| SceneError::UnknownPrototype { node: node_name(), prototype: instance.prototype.clone() })?;
+            node.instance = Some(Instance { prototype, time_offset: instance.time_offset });
+        }
+        Ok(node)
+    }
+
+    // Describes the graph as it is now. Nodes drawing something other than the meshes of the
+    // scene, e.g. a mesh uploaded by hand, are written without a mesh.
+    pub fn describe(&self) -> Result<SceneDescription, SceneError> {
+        let prototypes = self.graph.prototypes().iter()
+            .map(|&id| self.describe_node(id))
+            .collect::<Result<_, _>>()?;
+        let nodes = self.graph.roots().iter()
+            .map(|&id| self.describe_node(id))
+            .collect::<Result<_, _>>()?;
+        Ok(SceneDescription {
+            meshes: self.meshes.iter().map(|m| m.description.clone()).collect(),
+            prototypes,
+            nodes,
+        })
+    }
+
+    // internal helper, describes the node and everything below it
+    fn describe_node(&self, id: NodeId) -> Result<NodeDescription, SceneError> {
+        let node = &self.graph[id];
+        let q = node.orientation.coords;
+        let instance = match node.instance {
+            Some(instance) => Some(InstanceDescription {
+                prototype: self.graph.get(instance.prototype)
+                    .ok_or_else(|| SceneError::MissingPrototype { node: self.graph.path(id) })?
+                    .name.clone().ok_or(SceneError::UnnamedPrototype)?,
+                time_offset: instance.time_offset,
+            }),
+            None => None,
+        };
+        Ok(NodeDescription {
+            name: node.name.clone(),
+            mesh: self.meshes.iter()
+                .find(|m| node.vao_id != 0 && m.gpu.vao_id() == node.vao_id)
+                .map(|m| m.description.name.clone()),
+            position: tuple3(&node.position),
+            orientation: Orientation::Quat(q.x, q.y, q.z, q.w),
+            scale: tuple3(&node.scale),
+            reference_point: tuple3(&node.reference_point),
+            animation: node.animation.map(|a| match a {
+                Animation::Spin { axis, speed } => AnimationDescription::Spin { axis: tuple3(&axis), speed },
+                Animation::Heading => AnimationDescription::Heading,
+            }),
+            instance,
+            children: self.graph.children(id).iter()
+                .map(|&child| self.describe_node(child))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    // Writes the graph as it is now to a scene file, which loads back into the same scene
+    pub fn save(&self, path: &str) -> Result<(), SceneError> {
+        let description = self.describe()?;
+        let text = ron::ser::to_string_pretty(&description, ron::ser::PrettyConfig::new())
+            .map_err(|error| SceneError::Write { path: path.to_string(), error })?;
+        fs::write(path, text).map_err(|error| SceneError::Io { path: path.to_string(), error })
+    }
+}
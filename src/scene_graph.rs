@@ -1,24 +1,25 @@
 extern crate nalgebra_glm as glm;
 
-use std::mem::ManuallyDrop;
-use std::pin::Pin;
-
-// Used to create an unholy abomination upon which you should not cast your gaze. This ended up
-// being a necessity due to wanting to keep the code written by students as "straight forward" as
-// possible. It is very very double plus ungood Rust, and intentionally leaks memory like a sieve.
-// But it works, and you're more than welcome to pretend it doesn't exist! In case you're curious
-// about how it works: It allocates memory on the heap (Box), promises to prevent it from being
-// moved or deallocated until dropped (Pin) and finally prevents the compiler from dropping it
-// automatically at all (ManuallyDrop).
-// ...
-// If that sounds like a janky solution, it's because it is!
-// Prettier, Rustier and better solutions were tried numerous times, but were all found wanting of
-// having what I arbitrarily decided to be the required level of "simplicity of use".
-pub type Node = ManuallyDrop<Pin<Box<SceneNode>>>;
+use crate::mesh::Bounds;
+use crate::toolbox;
 
+use std::collections::VecDeque;
+use std::ops::{Index, IndexMut};
+
+// A simpler version of what a node draws, used once the node covers less than `screen_size` of
+// the height of the screen
+#[derive(Clone, Copy)]
+pub struct Lod {
+    pub vao_id      : u32,
+    pub index_count : i32,
+    pub screen_size : f32,
+}
+
+#[derive(Clone)]
 pub struct SceneNode {
+    pub name            : Option<String>, // What I'm called, for finding me again
     pub position        : glm::Vec3,   // Where I am in relation to my parent
-    pub rotation        : glm::Vec3,   // How I should be rotated
+    pub orientation     : glm::Quat,   // How I should be rotated, kept at unit length
     pub scale           : glm::Vec3,   // How I should be scaled
     pub reference_point : glm::Vec3,   // About which point I shall rotate about
 
@@ -26,52 +27,216 @@ pub struct SceneNode {
 
     pub vao_id      : u32,             // What I should draw
     pub index_count : i32,             // How much of it I shall draw
+    pub primitive   : gl::types::GLenum, // What the indices make up, see GpuMesh::primitive
+    pub texture_id  : u32,             // What I should be painted with, 0 if nothing
+    pub normal_map_id : u32,           // How bumpy I should look, 0 if not at all
+    pub bounds      : Option<Bounds>,  // How much space what I draw takes up, before transforming
+    pub lods        : Vec<Lod>,        // Cheaper stand-ins for when I'm far away, finest first
+    pub animation   : Option<Animation>, // How I move on my own, see SceneGraph::animate
+    pub instance    : Option<Instance>,  // Another subtree I draw a copy of, see SceneGraph
+}
 
-    pub children: Vec<*mut SceneNode>, // Those I command
+// Movement a node does on its own as time passes
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Animation {
+    // Turns about `axis` at `speed` radians per second
+    Spin { axis: glm::Vec3, speed: f32 },
+    // Flies the path of toolbox::simple_heading_animation, rolling, pitching and turning with it
+    Heading,
 }
 
-impl SceneNode {
+impl Animation {
+    // Poses the position and orientation for the given time
+    pub fn apply(&self, position: &mut glm::Vec3, orientation: &mut glm::Quat, time: f32) {
+        match *self {
+            Animation::Spin { axis, speed } => {
+                *orientation = glm::quat_angle_axis(speed * time, &glm::normalize(&axis));
+            },
+            Animation::Heading => {
+                let heading = toolbox::simple_heading_animation(time);
+                position.x = heading.x;
+                position.z = heading.z;
+                *orientation = quat_from_euler(&glm::vec3(heading.pitch, heading.yaw, heading.roll), EulerOrder::Zxy);
+            },
+        }
+    }
+}
+
+// Makes a node draw the subtree of `prototype` as if it were its own children. Animations in the
+// prototype run `time_offset` seconds ahead for this copy, so copies don't move in lockstep.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Instance {
+    pub prototype   : NodeId,
+    pub time_offset : f32,
+}
+
+// Instances of prototypes containing instances are followed this deep, which also stops a
+// prototype that contains an instance of itself
+const MAX_INSTANCE_DEPTH: usize = 8;
 
-    pub fn new() -> Node {
-        ManuallyDrop::new(Pin::new(Box::new(SceneNode {
+// The order Euler angles are applied in, e.g. Zxy rolls about Z first, then pitches about X and
+// finally turns about Y, which is how an aircraft heading is usually given
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EulerOrder {
+    Xyz,
+    Xzy,
+    Yxz,
+    Yzx,
+    Zxy,
+    Zyx,
+}
+
+// The rotation by `angles` (about X, Y and Z respectively) in the given order
+pub fn quat_from_euler(angles: &glm::Vec3, order: EulerOrder) -> glm::Quat {
+    let x = glm::quat_angle_axis(angles.x, &glm::vec3(1.0, 0.0, 0.0));
+    let y = glm::quat_angle_axis(angles.y, &glm::vec3(0.0, 1.0, 0.0));
+    let z = glm::quat_angle_axis(angles.z, &glm::vec3(0.0, 0.0, 1.0));
+    // The first rotation applied is the rightmost one
+    match order {
+        EulerOrder::Xyz => z * y * x,
+        EulerOrder::Xzy => y * z * x,
+        EulerOrder::Yxz => z * x * y,
+        EulerOrder::Yzx => x * z * y,
+        EulerOrder::Zxy => y * x * z,
+        EulerOrder::Zyx => x * y * z,
+    }
+}
+
+// Scales and rotates about the reference point, then moves into position
+fn transformation_matrix(position: &glm::Vec3, orientation: &glm::Quat, scale: &glm::Vec3, reference_point: &glm::Vec3) -> glm::Mat4 {
+    glm::translation(position)
+        * glm::translation(reference_point)
+        * glm::quat_to_mat4(orientation)
+        * glm::scaling(scale)
+        * glm::translation(&-reference_point)
+}
+
+// Spherical interpolation between two orientations, taking the shortest way around
+pub fn slerp(from: &glm::Quat, to: &glm::Quat, t: f32) -> glm::Quat {
+    let to = if glm::quat_dot(from, to) < 0.0 { -to } else { *to };
+    glm::quat_normalize(&glm::quat_slerp(from, &to, t))
+}
+
+impl Default for SceneNode {
+    fn default() -> SceneNode {
+        SceneNode {
+            name            : None,
             position        : glm::zero(),
-            rotation        : glm::zero(),
+            orientation     : glm::quat_identity(),
             scale           : glm::vec3(1.0, 1.0, 1.0),
             reference_point : glm::zero(),
             current_transformation_matrix: glm::identity(),
             vao_id          : 0,
             index_count     : -1,
-            children        : vec![],
-        })))
+            primitive       : gl::TRIANGLES,
+            texture_id      : 0,
+            normal_map_id   : 0,
+            bounds          : None,
+            lods            : vec![],
+            animation       : None,
+            instance        : None,
+        }
     }
+}
 
-    pub fn from_vao(vao_id: u32, index_count: i32) -> Node {
-        ManuallyDrop::new(Pin::new(Box::new(SceneNode {
-            position        : glm::zero(),
-            rotation        : glm::zero(),
-            scale           : glm::vec3(1.0, 1.0, 1.0),
-            reference_point : glm::zero(),
-            current_transformation_matrix: glm::identity(),
-            vao_id,
-            index_count,
-            children: vec![],
-        })))
+impl SceneNode {
+    // Sets my orientation from Euler angles (about X, Y and Z respectively) applied in `order`
+    #[allow(dead_code)]
+    pub fn set_euler(&mut self, angles: &glm::Vec3, order: EulerOrder) {
+        self.orientation = quat_from_euler(angles, order);
     }
 
-    pub fn add_child(&mut self, child: &SceneNode) {
-        self.children.push(child as *const SceneNode as *mut SceneNode)
+    // Sets my orientation to a rotation of `angle` radians about `axis`
+    #[allow(dead_code)]
+    pub fn set_axis_angle(&mut self, axis: &glm::Vec3, angle: f32) {
+        self.orientation = glm::quat_angle_axis(angle, &glm::normalize(axis));
     }
 
+    // Turns me so my -Z axis, the way OpenGL cameras and our models face, points from my position
+    // towards `target`, with my Y axis as close to `up` as it can be. Both are in my parent's
+    // space. Nothing happens if the target is where I am.
     #[allow(dead_code)]
-    pub fn get_child(&mut self, index: usize) -> &mut SceneNode {
-        unsafe {
-            &mut (*self.children[index])
+    pub fn look_at(&mut self, target: &glm::Vec3, up: &glm::Vec3) {
+        let direction = target - self.position;
+        if glm::length(&direction) < 1e-6 {
+            return;
+        }
+        let back = -glm::normalize(&direction);
+        let mut right = glm::cross(up, &back);
+        if glm::length(&right) < 1e-6 {
+            // Looking straight along `up`, any right will do
+            right = glm::cross(&glm::vec3(1.0, 0.0, 0.0), &back);
+            if glm::length(&right) < 1e-6 {
+                right = glm::cross(&glm::vec3(0.0, 0.0, 1.0), &back);
+            }
         }
+        let right = glm::normalize(&right);
+        let true_up = glm::cross(&back, &right);
+        let m = glm::mat3(
+            right.x, true_up.x, back.x,
+            right.y, true_up.y, back.y,
+            right.z, true_up.z, back.z,
+        );
+        self.orientation = glm::quat_normalize(&glm::mat3_to_quat(&m));
     }
 
+    // Sets my orientation `t` of the way from `from` to `to`, for animating between two poses
     #[allow(dead_code)]
-    pub fn get_n_children(&self) -> usize {
-        self.children.len()
+    pub fn set_slerp(&mut self, from: &glm::Quat, to: &glm::Quat, t: f32) {
+        self.orientation = slerp(from, to, t);
+    }
+
+    // My transformation relative to my parent: scaled and rotated about the reference point, then
+    // moved into position
+    pub fn local_transformation_matrix(&self) -> glm::Mat4 {
+        transformation_matrix(&self.position, &self.orientation, &self.scale, &self.reference_point)
+    }
+
+    // My local transformation with my animation, if any, posed for the given time. Doesn't change
+    // me, so a prototype can be posed differently for each instance.
+    pub fn animated_transformation_matrix(&self, time: f32) -> glm::Mat4 {
+        let (mut position, mut orientation) = (self.position, self.orientation);
+        if let Some(animation) = &self.animation {
+            animation.apply(&mut position, &mut orientation, time);
+        }
+        transformation_matrix(&position, &orientation, &self.scale, &self.reference_point)
+    }
+
+    // The opposite of local_transformation_matrix, sets my position, rotation and scale so I end
+    // up with the given transformation, keeping my reference point. Shearing can't be expressed
+    // and is lost.
+    pub fn set_local_transformation_matrix(&mut self, m: &glm::Mat4) {
+        let upper = glm::mat4_to_mat3(m);
+        let scale = glm::vec3(upper.column(0).norm(), upper.column(1).norm(), upper.column(2).norm());
+        if scale.x == 0.0 || scale.y == 0.0 || scale.z == 0.0 {
+            return; // Squashed flat, there's no rotation to recover
+        }
+        let rotation = glm::mat3(
+            upper[(0, 0)] / scale.x, upper[(0, 1)] / scale.y, upper[(0, 2)] / scale.z,
+            upper[(1, 0)] / scale.x, upper[(1, 1)] / scale.y, upper[(1, 2)] / scale.z,
+            upper[(2, 0)] / scale.x, upper[(2, 1)] / scale.y, upper[(2, 2)] / scale.z,
+        );
+        let translation = glm::vec3(m[(0, 3)], m[(1, 3)], m[(2, 3)]);
+        self.scale = scale;
+        self.orientation = glm::quat_normalize(&glm::mat3_to_quat(&rotation));
+        self.position = translation - self.reference_point + upper * self.reference_point;
+    }
+
+    // How much of the screen height my bounding sphere covers when drawn with the `world` matrix,
+    // given the view projection matrix of the camera. Assumes the view matrix doesn't scale
+    // anything.
+    pub fn screen_size(&self, world: &glm::Mat4, view_projection: &glm::Mat4) -> Option<f32> {
+        let bounds = self.bounds?.transformed(world);
+        let c = bounds.center;
+        let distance = (view_projection * glm::vec4(c.x, c.y, c.z, 1.0)).w;
+        if distance <= bounds.radius {
+            return None; // The camera is inside or too close to tell
+        }
+        // For a perspective projection, the Y row of the view projection matrix has the length
+        // 1 / tan(fov_y / 2), which converts sizes at distance 1 to screen heights
+        let focal = glm::length(&view_projection.row(1).transpose().xyz());
+        Some(bounds.radius * focal / distance)
     }
 
     #[allow(dead_code)]
@@ -79,11 +244,14 @@ impl SceneNode {
         let m = self.current_transformation_matrix;
         println!(
 "SceneNode {{
+    Name:      {}
     VAO:       {}
     Indices:   {}
-    Children:  {}
+    Texture:   {}
+    NormalMap: {}
+    LODs:      {}
     Position:  [{:.2}, {:.2}, {:.2}]
-    Rotation:  [{:.2}, {:.2}, {:.2}]
+    Rotation:  [{:.2}, {:.2}, {:.2}, {:.2}]
     Reference: [{:.2}, {:.2}, {:.2}]
     Current Transformation Matrix:
         {:.2}  {:.2}  {:.2}  {:.2}
@@ -91,15 +259,19 @@ impl SceneNode {
         {:.2}  {:.2}  {:.2}  {:.2}
         {:.2}  {:.2}  {:.2}  {:.2}
 }}",
+            self.name.as_deref().unwrap_or("-"),
             self.vao_id,
             self.index_count,
-            self.children.len(),
+            self.texture_id,
+            self.normal_map_id,
+            self.lods.len(),
             self.position.x,
             self.position.y,
             self.position.z,
-            self.rotation.x,
-            self.rotation.y,
-            self.rotation.z,
+            self.orientation.i,
+            self.orientation.j,
+            self.orientation.k,
+            self.orientation.w,
             self.reference_point.x,
             self.reference_point.y,
             self.reference_point.z,
@@ -113,20 +285,730 @@ impl SceneNode {
 }
 
 
-// You can also use square brackets to access the children of a SceneNode
-use std::ops::{Index, IndexMut};
-impl Index<usize> for SceneNode {
-    type Output = SceneNode;
-    fn index(&self, index: usize) -> &SceneNode {
-        unsafe {
-            & *(self.children[index] as *const SceneNode)
+// A scene graph that owns its nodes. The nodes live in one Vec and refer to each other by NodeId
+// instead of by pointer, so nothing leaks and there's no unsafe code.
+//
+//     let mut graph = SceneGraph::new();
+//     let terrain = graph.add(SceneNode::default());
+//     let body = graph.add(SceneNode { vao_id, index_count, ..SceneNode::default() });
+//     graph.add_child(terrain, body);
+//     graph[body].position.y = 5.0;
+//     graph.update_transformations();
+//
+// Any mutable access to a node, through square brackets or get_mut, marks it as changed, and
+// update_transformations only recomputes the matrices of changed nodes and the nodes below them.
+//
+// To draw the same subtree several times, add it with add_prototype, which keeps it out of the
+// scene, and give each copy a node with an Instance of it:
+//
+//     let heli = graph.add_prototype(body);
+//     let copy = graph.add(SceneNode { instance: Some(Instance { prototype: heli, time_offset: 1.5 }), ..SceneNode::default() });
+//     graph.add_child(terrain, copy);
+
+// A handle to a node in a SceneGraph. The generation tells apart the nodes that have used the same
+// slot over time, so a handle to a removed node can never reach whatever replaced it.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct NodeId {
+    index      : u32,
+    generation : u32,
+}
+
+struct Entry {
+    node        : SceneNode,
+    parent      : Option<NodeId>,
+    children    : Vec<NodeId>,
+    local       : glm::Mat4,   // The node's local transformation as of the last update
+    local_dirty : bool,        // The node may have changed since `local` was computed
+    world_dirty : bool,        // The node has moved to another parent since the last update
+}
+
+struct Slot {
+    generation : u32,
+    entry      : Option<Entry>,
+}
+
+#[derive(Default)]
+pub struct SceneGraph {
+    slots : Vec<Slot>,
+    free  : Vec<u32>,      // Slots of removed nodes, to be reused
+    roots : Vec<NodeId>,   // The nodes without a parent, in the order they were added
+    prototypes : Vec<NodeId>, // Nodes without a parent that are only drawn through instances
+    time  : f32,           // The time of the last animate
+    recomputed : usize,    // How many world matrices the last update had to recompute
+}
+
+impl SceneGraph {
+    pub fn new() -> SceneGraph {
+        SceneGraph::default()
+    }
+
+    // Adds a node without a parent
+    pub fn add(&mut self, node: SceneNode) -> NodeId {
+        let entry = Some(Entry {
+            node,
+            parent: None,
+            children: vec![],
+            local: glm::identity(),
+            local_dirty: true,
+            world_dirty: true,
+        });
+        let id = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.entry = entry;
+                NodeId { index, generation: slot.generation }
+            },
+            None => {
+                self.slots.push(Slot { generation: 0, entry });
+                NodeId { index: self.slots.len() as u32 - 1, generation: 0 }
+            },
+        };
+        self.roots.push(id);
+        id
+    }
+
+    // Adds a node without a parent that isn't part of the scene itself, but is drawn, along with
+    // its children, wherever an Instance of it is
+    pub fn add_prototype(&mut self, node: SceneNode) -> NodeId {
+        let id = self.add(node);
+        self.roots.retain(|&root| root != id);
+        self.prototypes.push(id);
+        id
+    }
+
+    // Makes `child`, which must not have a parent yet, the last child of `parent`
+    pub fn add_child(&mut self, parent: NodeId, child: NodeId) {
+        assert!(self.entry(child).parent.is_none(), "Node {:?} already has a parent", child);
+        assert!(!self.is_ancestor(child, parent), "Node {:?} can't be its own ancestor", child);
+        self.entry_mut(parent).children.push(child);
+        let entry = self.entry_mut(child);
+        entry.parent = Some(parent);
+        entry.world_dirty = true;
+        self.roots.retain(|&root| root != child);
+        self.prototypes.retain(|&prototype| prototype != child);
+    }
+
+    // The first node with the given name, searching breadth first from the roots
+    #[allow(dead_code)]
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.find_all(|node| node.name.as_deref() == Some(name)).next()
+    }
+
+    // Follows a slash separated path of names from the roots, e.g. "terrain/body/main_rotor".
+    // Where several siblings share a name, the first one is picked.
+    #[allow(dead_code)]
+    pub fn find_path(&self, path: &str) -> Option<NodeId> {
+        let mut candidates = self.roots.as_slice();
+        let mut found = None;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let id = *candidates.iter().find(|&&id| self[id].name.as_deref() == Some(name))?;
+            candidates = self.children(id);
+            found = Some(id);
+        }
+        found
+    }
+
+    // The slash separated path of names leading to the node, with "?" for unnamed nodes
+    #[allow(dead_code)]
+    pub fn path(&self, id: NodeId) -> String {
+        let mut names = vec![];
+        let mut current = Some(id);
+        while let Some(c) = current {
+            names.push(self[c].name.as_deref().unwrap_or("?"));
+            current = self.entry(c).parent;
+        }
+        names.reverse();
+        names.join("/")
+    }
+
+    // Every node in the graph, breadth first from the roots and then the prototypes
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &SceneNode)> + '_ {
+        let mut queue: VecDeque<NodeId> = self.roots.iter().chain(&self.prototypes).cloned().collect();
+        std::iter::from_fn(move || {
+            let id = queue.pop_front()?;
+            queue.extend(self.children(id));
+            Some((id, &self[id]))
+        })
+    }
+
+    // Every node the predicate accepts, breadth first from the roots, e.g.
+    // `graph.find_all(|node| node.name.as_deref() == Some("door"))`
+    pub fn find_all<'a, P>(&'a self, predicate: P) -> impl Iterator<Item = NodeId> + 'a
+        where P: Fn(&SceneNode) -> bool + 'a
+    {
+        self.iter().filter(move |(_, node)| predicate(node)).map(|(id, _)| id)
+    }
+
+    // Removes the node and everything below it. Their ids become invalid, and won't be handed out
+    // again.
+    #[allow(dead_code)]
+    pub fn remove(&mut self, id: NodeId) {
+        self.detach(id, false);
+        self.roots.retain(|&root| root != id);
+        self.prototypes.retain(|&prototype| prototype != id);
+        let mut stack = vec![id];
+        while let Some(current) = stack.pop() {
+            let slot = &mut self.slots[current.index as usize];
+            if let Some(entry) = slot.entry.take() {
+                stack.extend(entry.children);
+            }
+            slot.generation += 1;
+            self.free.push(current.index);
+        }
+    }
+
+    // Unhooks the node, along with everything below it, from its parent, making it a root. With
+    // `keep_world_transform` the node's transformation is adjusted so it stays where it was on
+    // screen, otherwise its transformation is now relative to the world instead of the old parent.
+    pub fn detach(&mut self, id: NodeId, keep_world_transform: bool) {
+        let parent = match self.entry(id).parent {
+            Some(parent) => parent,
+            None => return,
+        };
+        if keep_world_transform {
+            let world = self.world_transformation_matrix(id);
+            self[id].set_local_transformation_matrix(&world);
+        }
+        self.entry_mut(parent).children.retain(|&child| child != id);
+        let entry = self.entry_mut(id);
+        entry.parent = None;
+        entry.world_dirty = true;
+        self.roots.push(id);
+    }
+
+    // Moves the node, along with everything below it, to be the last child of `new_parent`. With
+    // `keep_world_transform` it stays where it was, e.g. a door falling off stays where it fell
+    // instead of following the new parent around.
+    pub fn reparent(&mut self, id: NodeId, new_parent: NodeId, keep_world_transform: bool) {
+        assert!(!self.is_ancestor(id, new_parent), "Node {:?} can't be its own ancestor", id);
+        let world = self.world_transformation_matrix(id);
+        self.detach(id, false);
+        if keep_world_transform {
+            let parent_world = self.world_transformation_matrix(new_parent);
+            let local = glm::inverse(&parent_world) * world;
+            self[id].set_local_transformation_matrix(&local);
+        }
+        self.add_child(new_parent, id);
+    }
+
+    // The transformation from the node to the world, computed from the node and its ancestors as
+    // they are now, rather than as they were at the last update
+    pub fn world_transformation_matrix(&self, id: NodeId) -> glm::Mat4 {
+        let mut m = self[id].local_transformation_matrix();
+        let mut current = self.entry(id).parent;
+        while let Some(parent) = current {
+            m = self[parent].local_transformation_matrix() * m;
+            current = self.entry(parent).parent;
+        }
+        m
+    }
+
+    // Poses every animated node for the given time in seconds. Instances run their own animation
+    // and that of their prototype `time_offset` ahead.
+    pub fn animate(&mut self, time: f32) {
+        self.time = time;
+        for slot in self.slots.iter_mut() {
+            if let Some(entry) = slot.entry.as_mut() {
+                let node = &mut entry.node;
+                if let Some(animation) = node.animation {
+                    let t = time + node.instance.map_or(0.0, |instance| instance.time_offset);
+                    animation.apply(&mut node.position, &mut node.orientation, t);
+                    entry.local_dirty = true;
+                }
+            }
+        }
+    }
+
+    // Brings current_transformation_matrix up to date for every node in the scene. Nodes that
+    // haven't been touched since the last update, and whose ancestors haven't either, are skipped.
+    // Prototypes are posed for each instance while drawing instead.
+    pub fn update_transformations(&mut self) {
+        self.recomputed = 0;
+        for i in 0..self.roots.len() {
+            let root = self.roots[i];
+            self.update_subtree(root, &glm::identity(), false);
+        }
+    }
+
+    fn update_subtree(&mut self, id: NodeId, parent_world: &glm::Mat4, parent_changed: bool) {
+        let entry = self.entry_mut(id);
+        if entry.local_dirty {
+            let local = entry.node.local_transformation_matrix();
+            // Touching a node without changing its transformation, e.g. to swap its texture,
+            // doesn't need to move anything
+            entry.world_dirty |= local != entry.local;
+            entry.local = local;
+            entry.local_dirty = false;
+        }
+        let changed = parent_changed || entry.world_dirty;
+        if changed {
+            entry.node.current_transformation_matrix = parent_world * entry.local;
+            entry.world_dirty = false;
+        }
+        let world = entry.node.current_transformation_matrix;
+        if changed {
+            self.recomputed += 1;
+        }
+        for i in 0..self.entry(id).children.len() {
+            let child = self.entry(id).children[i];
+            self.update_subtree(child, &world, changed);
+        }
+    }
+
+    // Calls `visitor` with every node, parents before their children and each subtree finished
+    // before the next sibling, e.g. for printing or exporting the hierarchy. Like iter, the
+    // scene comes first and the prototypes after it.
+    #[allow(dead_code)]
+    pub fn visit_depth_first<F: FnMut(NodeId, &SceneNode)>(&self, mut visitor: F) {
+        let mut stack: Vec<NodeId> = self.roots.iter().chain(&self.prototypes).rev().cloned().collect();
+        while let Some(id) = stack.pop() {
+            visitor(id, &self[id]);
+            stack.extend(self.children(id).iter().rev());
+        }
+    }
+
+    // Calls `visitor` with every node, level by level from the roots and prototypes down
+    #[allow(dead_code)]
+    pub fn visit_breadth_first<F: FnMut(NodeId, &SceneNode)>(&self, mut visitor: F) {
+        for (id, node) in self.iter() {
+            visitor(id, node);
+        }
+    }
+
+    // Calls `renderer` with every node that has something to draw, along with the matrix to draw
+    // it with. The renderer does the actual drawing, so the same traversal works for rendering,
+    // picking or exporting. Prototype nodes are passed once for every instance of them. Call
+    // update_transformations first.
+    pub fn draw<R: FnMut(&SceneNode, &glm::Mat4)>(&self, mut renderer: R) {
+        for &root in &self.roots {
+            self.draw_subtree(root, &self[root].current_transformation_matrix, None, 0, &mut renderer);
+        }
+    }
+
+    // `posed_at` is None for the nodes of the scene, which use their matrices from the last
+    // update, and the animation time for the nodes of a prototype, which are posed as they go
+    fn draw_subtree<R>(&self, id: NodeId, world: &glm::Mat4, posed_at: Option<f32>, depth: usize, renderer: &mut R)
+        where R: FnMut(&SceneNode, &glm::Mat4)
+    {
+        let node = &self[id];
+        if node.vao_id != 0 {
+            renderer(node, world);
+        }
+        if let Some(instance) = node.instance {
+            if depth < MAX_INSTANCE_DEPTH && self.contains(instance.prototype) {
+                let time = posed_at.unwrap_or(self.time) + instance.time_offset;
+                let prototype_world = world * self.posed_matrix(instance.prototype, time);
+                self.draw_subtree(instance.prototype, &prototype_world, Some(time), depth + 1, renderer);
+            }
         }
+        for &child in self.children(id) {
+            let child_world = match posed_at {
+                Some(time) => world * self.posed_matrix(child, time),
+                None => self[child].current_transformation_matrix,
+            };
+            self.draw_subtree(child, &child_world, posed_at, depth, renderer);
+        }
+    }
+
+    // internal helper, the local matrix of a node in a prototype posed for the given time. Instances
+    // in there run their own animation `time_offset` further ahead, just like animate does for
+    // the instances in the scene, so offsets add up through nested instances.
+    fn posed_matrix(&self, id: NodeId, time: f32) -> glm::Mat4 {
+        let node = &self[id];
+        node.animated_transformation_matrix(time + node.instance.map_or(0.0, |instance| instance.time_offset))
+    }
+
+    // The world space bounds of everything the node and the nodes below it draw, including the
+    // prototypes drawn by instances, e.g. for culling or picking a whole helicopter. Uses the
+    // matrices from the last update. Nodes of a prototype aren't in the world, and are measured in
+    // the space of the prototype instead. None if nothing in the subtree has bounds.
+    #[allow(dead_code)]
+    pub fn world_bounds(&self, id: NodeId) -> Option<Bounds> {
+        let mut top = id;
+        while let Some(parent) = self.entry(top).parent {
+            top = parent;
+        }
+        let (world, posed_at) = if self.prototypes.contains(&top) {
+            (self.world_transformation_matrix(id), Some(self.time))
+        } else {
+            (self[id].current_transformation_matrix, None)
+        };
+        let mut bounds: Option<Bounds> = None;
+        self.draw_subtree(id, &world, posed_at, 0, &mut |node: &SceneNode, world: &glm::Mat4| {
+            if let Some(b) = node.bounds.map(|b| b.transformed(world)) {
+                bounds = Some(bounds.map_or(b, |a| a.union(&b)));
+            }
+        });
+        bounds
+    }
+
+    // How many nodes the last update_transformations had to recompute, for profiling
+    #[allow(dead_code)]
+    pub fn recomputed_nodes(&self) -> usize {
+        self.recomputed
+    }
+
+    fn entry_ref(&self, id: NodeId) -> Option<&Entry> {
+        self.slots.get(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.entry.as_ref())
+    }
+
+    fn entry_ref_mut(&mut self, id: NodeId) -> Option<&mut Entry> {
+        self.slots.get_mut(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.entry.as_mut())
+    }
+
+    fn entry(&self, id: NodeId) -> &Entry {
+        self.entry_ref(id).unwrap_or_else(|| panic!("Node {:?} is not in the scene graph", id))
+    }
+
+    fn entry_mut(&mut self, id: NodeId) -> &mut Entry {
+        self.entry_ref_mut(id).unwrap_or_else(|| panic!("Node {:?} is not in the scene graph", id))
+    }
+
+    // Whether `ancestor` is `id` or somewhere above it
+    pub fn is_ancestor(&self, ancestor: NodeId, id: NodeId) -> bool {
+        let mut current = Some(id);
+        while let Some(c) = current {
+            if c == ancestor {
+                return true;
+            }
+            current = self.entry(c).parent;
+        }
+        false
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.entry_ref(id).is_some()
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, id: NodeId) -> Option<&SceneNode> {
+        self.entry_ref(id).map(|e| &e.node)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut SceneNode> {
+        self.entry_ref_mut(id).map(|e| {
+            e.local_dirty = true;
+            &mut e.node
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    // The prototypes, in the order they were added
+    pub fn prototypes(&self) -> &[NodeId] {
+        &self.prototypes
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.entry(id).parent
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.entry(id).children
+    }
+
+    #[allow(dead_code)]
+    pub fn get_child(&self, id: NodeId, index: usize) -> NodeId {
+        self.entry(id).children[index]
+    }
+
+    #[allow(dead_code)]
+    pub fn get_n_children(&self, id: NodeId) -> usize {
+        self.entry(id).children.len()
+    }
+
+    // The number of nodes in the graph
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.entry.is_some()).count()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Square brackets give the node itself, and panic if it has been removed. Mutable access marks the
+// node as changed.
+impl Index<NodeId> for SceneGraph {
+    type Output = SceneNode;
+    fn index(&self, id: NodeId) -> &SceneNode {
+        &self.entry(id).node
+    }
+}
+impl IndexMut<NodeId> for SceneGraph {
+    fn index_mut(&mut self, id: NodeId) -> &mut SceneNode {
+        let entry = self.entry_mut(id);
+        entry.local_dirty = true;
+        &mut entry.node
     }
 }
-impl IndexMut<usize> for SceneNode {
-    fn index_mut(&mut self, index: usize) -> &mut SceneNode {
-        unsafe {
-            &mut (*self.children[index])
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &glm::Mat4, b: &glm::Mat4) {
+        assert!((a - b).abs().max() < 1e-4, "{} is not {}", a, b);
+    }
+
+    // A node turned, scaled and offset in every way a transformation can be
+    fn posed(position: glm::Vec3, angles: glm::Vec3, scale: f32, reference_point: glm::Vec3) -> SceneNode {
+        let mut node = SceneNode {
+            position,
+            scale: glm::vec3(scale, scale, scale),
+            reference_point,
+            ..SceneNode::default()
+        };
+        node.set_euler(&angles, EulerOrder::Xyz);
+        node
+    }
+
+    #[test]
+    fn reparent_can_keep_the_world_transform() {
+        let mut graph = SceneGraph::new();
+        let terrain = graph.add(posed(glm::vec3(1.0, 2.0, 3.0), glm::vec3(0.0, 0.4, 0.0), 0.5, glm::vec3(1.0, 0.0, 0.0)));
+        let body = graph.add(posed(glm::vec3(5.0, 0.0, 1.0), glm::vec3(0.3, 1.2, -0.4), 2.0, glm::vec3(0.5, 0.2, 0.0)));
+        let door = graph.add(posed(glm::vec3(0.0, 0.0, -3.0), glm::vec3(0.7, -0.2, 0.1), 1.0, glm::vec3(0.35, 2.3, 10.4)));
+        graph.add_child(terrain, body);
+        graph.add_child(body, door);
+
+        let world = graph.world_transformation_matrix(door);
+        graph.reparent(door, terrain, true);
+        assert_eq!(graph.parent(door), Some(terrain));
+        assert_eq!(graph.children(body), &[] as &[NodeId]);
+        assert_close(&graph.world_transformation_matrix(door), &world);
+        graph.update_transformations();
+        assert_close(&graph[door].current_transformation_matrix, &world);
+
+        graph.detach(door, true);
+        assert_eq!(graph.parent(door), None);
+        assert!(graph.roots().contains(&door));
+        assert_close(&graph.world_transformation_matrix(door), &world);
+    }
+
+    #[test]
+    fn reparent_without_keeping_the_world_transform_keeps_the_local_one() {
+        let mut graph = SceneGraph::new();
+        let body = graph.add(posed(glm::vec3(5.0, 0.0, 1.0), glm::vec3(0.3, 1.2, -0.4), 2.0, glm::vec3(0.5, 0.2, 0.0)));
+        let terrain = graph.add(SceneNode::default());
+        let door = graph.add(posed(glm::vec3(0.0, 0.0, -3.0), glm::vec3(0.7, -0.2, 0.1), 1.0, glm::vec3(0.35, 2.3, 10.4)));
+        graph.add_child(terrain, door);
+
+        let local = graph[door].local_transformation_matrix();
+        graph.reparent(door, body, false);
+        assert_close(&graph[door].local_transformation_matrix(), &local);
+        assert_close(&graph.world_transformation_matrix(door), &(graph[body].local_transformation_matrix() * local));
+    }
+
+    #[test]
+    fn removed_ids_stay_invalid() {
+        let mut graph = SceneGraph::new();
+        let body = graph.add(SceneNode::default());
+        let door = graph.add(SceneNode::default());
+        graph.add_child(body, door);
+
+        graph.remove(body);
+        assert!(!graph.contains(body));
+        assert!(!graph.contains(door));
+        assert!(graph.get(door).is_none());
+        assert!(graph.is_empty());
+
+        // The new nodes reuse the slots, but not the ids
+        let reused = [graph.add(SceneNode::default()), graph.add(SceneNode::default())];
+        assert!(!reused.contains(&body) && !reused.contains(&door));
+        assert!(!graph.contains(body));
+        assert!(!graph.contains(door));
+        assert!(reused.iter().all(|&id| graph.contains(id)));
+        assert_eq!(graph.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be its own ancestor")]
+    fn add_child_rejects_cycles() {
+        let mut graph = SceneGraph::new();
+        let body = graph.add(SceneNode::default());
+        let door = graph.add(SceneNode::default());
+        graph.add_child(body, door);
+        graph.add_child(door, body);
+    }
+
+    #[test]
+    #[should_panic(expected = "can't be its own ancestor")]
+    fn reparent_rejects_cycles() {
+        let mut graph = SceneGraph::new();
+        let body = graph.add(SceneNode::default());
+        let door = graph.add(SceneNode::default());
+        graph.add_child(body, door);
+        graph.reparent(body, door, true);
+    }
+
+    // terrain with a body and a tree beside it, and a door and rotor on the body, all up to date
+    fn updated_scene() -> (SceneGraph, [NodeId; 5]) {
+        let mut graph = SceneGraph::new();
+        let ids = [(); 5].map(|_| graph.add(SceneNode::default()));
+        let [terrain, body, tree, door, rotor] = ids;
+        graph.add_child(terrain, body);
+        graph.add_child(terrain, tree);
+        graph.add_child(body, door);
+        graph.add_child(body, rotor);
+        graph.update_transformations();
+        assert_eq!(graph.recomputed_nodes(), 5);
+        (graph, ids)
+    }
+
+    #[test]
+    fn unchanged_scene_recomputes_nothing() {
+        let (mut graph, _) = updated_scene();
+        graph.update_transformations();
+        assert_eq!(graph.recomputed_nodes(), 0);
+    }
+
+    #[test]
+    fn changed_leaf_recomputes_only_itself() {
+        let (mut graph, [_, body, _, door, _]) = updated_scene();
+        graph[body].position.x = 1.0;
+        graph.update_transformations();
+        graph[door].position.y = 2.0;
+        graph.update_transformations();
+        assert_eq!(graph.recomputed_nodes(), 1);
+        assert_eq!(graph[door].current_transformation_matrix, glm::translation(&glm::vec3(1.0, 2.0, 0.0)));
+    }
+
+    #[test]
+    fn changed_parent_recomputes_its_subtree() {
+        let (mut graph, [_, body, _, door, rotor]) = updated_scene();
+        graph[body].position.x = 3.0;
+        graph.update_transformations();
+        assert_eq!(graph.recomputed_nodes(), 3);
+        for id in [body, door, rotor] {
+            assert_eq!(graph[id].current_transformation_matrix, glm::translation(&glm::vec3(3.0, 0.0, 0.0)));
+        }
+    }
+
+    #[test]
+    fn touching_without_moving_recomputes_nothing() {
+        let (mut graph, [terrain, body, _, door, _]) = updated_scene();
+        graph[terrain].texture_id = 3;
+        graph[body].position.x = 0.0;
+        graph.get_mut(door).unwrap().vao_id = 1;
+        graph.update_transformations();
+        assert_eq!(graph.recomputed_nodes(), 0);
+    }
+
+    #[test]
+    fn reparented_node_is_recomputed() {
+        let (mut graph, [_, body, tree, door, _]) = updated_scene();
+        graph[body].position.x = 3.0;
+        graph.update_transformations();
+        graph.reparent(door, tree, false);
+        graph.update_transformations();
+        assert_eq!(graph.recomputed_nodes(), 1);
+        assert_eq!(graph[door].current_transformation_matrix, glm::Mat4::identity());
+    }
+
+
+    fn spinning(vao_id: u32) -> SceneNode {
+        SceneNode {
+            vao_id,
+            animation: Some(Animation::Spin { axis: glm::vec3(0.0, 1.0, 0.0), speed: 1.0 }),
+            ..SceneNode::default()
         }
     }
+
+    fn instance_of(prototype: NodeId, time_offset: f32, position: glm::Vec3) -> SceneNode {
+        SceneNode { position, instance: Some(Instance { prototype, time_offset }), ..SceneNode::default() }
+    }
+
+    fn turned(angle: f32) -> glm::Mat4 {
+        glm::rotation(angle, &glm::vec3(0.0, 1.0, 0.0))
+    }
+
+    // What the renderer gets passed, by the vao of the node
+    fn drawn(graph: &SceneGraph) -> Vec<(u32, glm::Mat4)> {
+        let mut drawn = vec![];
+        graph.draw(|node, world| drawn.push((node.vao_id, *world)));
+        drawn
+    }
+
+    #[test]
+    fn instances_pose_their_prototype_at_their_own_time() {
+        let mut graph = SceneGraph::new();
+        let rotor = graph.add_prototype(spinning(1));
+        let offsets = [0.0, 0.5, 1.25];
+        for (i, &offset) in offsets.iter().enumerate() {
+            graph.add(instance_of(rotor, offset, glm::vec3(10.0 * i as f32, 0.0, 0.0)));
+        }
+        graph.animate(2.0);
+        graph.update_transformations();
+
+        let drawn = drawn(&graph);
+        assert_eq!(drawn.len(), offsets.len());
+        for (i, (&offset, &(vao_id, world))) in offsets.iter().zip(&drawn).enumerate() {
+            assert_eq!(vao_id, 1);
+            assert_close(&world, &(glm::translation(&glm::vec3(10.0 * i as f32, 0.0, 0.0)) * turned(2.0 + offset)));
+        }
+    }
+
+    #[test]
+    fn nested_instance_offsets_add_up() {
+        let mut graph = SceneGraph::new();
+        let rotor = graph.add_prototype(spinning(1));
+        let helicopter = graph.add_prototype(SceneNode::default());
+        let mount = graph.add(instance_of(rotor, 0.5, glm::vec3(0.0, 2.0, 0.0)));
+        graph.add_child(helicopter, mount);
+        graph.add(instance_of(helicopter, 0.25, glm::vec3(5.0, 0.0, 0.0)));
+        graph.animate(1.0);
+        graph.update_transformations();
+
+        let drawn = drawn(&graph);
+        assert_eq!(drawn.len(), 1);
+        assert_close(&drawn[0].1, &(glm::translation(&glm::vec3(5.0, 2.0, 0.0)) * turned(1.0 + 0.25 + 0.5)));
+    }
+
+    #[test]
+    fn animated_instance_in_a_prototype_runs_its_own_offset_ahead() {
+        let mut graph = SceneGraph::new();
+        let rotor = graph.add_prototype(spinning(1));
+        let helicopter = graph.add_prototype(SceneNode::default());
+        let mount = graph.add(SceneNode { vao_id: 2, ..spinning(0) });
+        graph[mount].instance = Some(Instance { prototype: rotor, time_offset: 0.5 });
+        graph.add_child(helicopter, mount);
+        graph.add(instance_of(helicopter, 0.25, glm::Vec3::zeros()));
+        graph.animate(1.0);
+        graph.update_transformations();
+
+        // The mount spins 0.25 + 0.5 ahead, and the rotor on it just as far again
+        let drawn = drawn(&graph);
+        assert_eq!(drawn.len(), 2);
+        assert_close(&drawn[0].1, &turned(1.75));
+        assert_close(&drawn[1].1, &(turned(1.75) * turned(1.75)));
+    }
+
+    #[test]
+    fn prototype_instancing_itself_stops() {
+        let mut graph = SceneGraph::new();
+        let prototype = graph.add_prototype(SceneNode { vao_id: 1, ..SceneNode::default() });
+        let recursion = graph.add(instance_of(prototype, 0.0, glm::vec3(1.0, 0.0, 0.0)));
+        graph.add_child(prototype, recursion);
+        graph.add(instance_of(prototype, 0.0, glm::Vec3::zeros()));
+        graph.update_transformations();
+
+        let drawn = drawn(&graph);
+        assert_eq!(drawn.len(), MAX_INSTANCE_DEPTH);
+        // Each copy one further along than the one it is inside of
+        for (depth, &(_, world)) in drawn.iter().enumerate() {
+            assert_close(&world, &glm::translation(&glm::vec3(depth as f32, 0.0, 0.0)));
+        }
+    }
+
 }
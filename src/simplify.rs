@@ -0,0 +1,331 @@
+extern crate nalgebra_glm as glm;
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::mesh::Mesh;
+
+// Mesh simplification by edge collapse, using the quadric error metric of Garland and Heckbert.
+// Each collapse merges one end of an edge into the other, so no new vertices are made and the
+// attributes of the survivor (colors, uvs, ...) are kept as they are.
+
+// Edges along holes and the outer rim are weighted this much more than the surface, so the
+// outline of e.g. a terrain doesn't get eaten away
+const BOUNDARY_WEIGHT: f64 = 1000.0;
+
+// A symmetric 4x4 matrix, stored as its upper triangle
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    // The squared distance to the plane ax + by + cz + d = 0, scaled by `weight`
+    fn plane(n: glm::DVec3, d: f64, weight: f64) -> Self {
+        let (a, b, c) = (n.x, n.y, n.z);
+        Quadric([a*a, a*b, a*c, a*d, b*b, b*c, b*d, c*c, c*d, d*d]).scaled(weight)
+    }
+
+    fn scaled(self, w: f64) -> Self {
+        let mut q = self.0;
+        q.iter_mut().for_each(|x| *x *= w);
+        Quadric(q)
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for i in 0..10 {
+            self.0[i] += other.0[i];
+        }
+    }
+
+    fn error(&self, v: &glm::DVec3) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (v.x, v.y, v.z);
+        q[0]*x*x + 2.0*q[1]*x*y + 2.0*q[2]*x*z + 2.0*q[3]*x
+            + q[4]*y*y + 2.0*q[5]*y*z + 2.0*q[6]*y
+            + q[7]*z*z + 2.0*q[8]*z
+            + q[9]
+    }
+}
+
+// A candidate collapse of point `from` into point `to`, valid only as long as neither of them
+// has changed since it was queued
+struct Collapse {
+    cost: f64,
+    from: usize,
+    to: usize,
+    versions: (u32, u32),
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool { self.cmp(other) == Ordering::Equal }
+}
+impl Eq for Collapse {}
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering { self.cost.total_cmp(&other.cost) }
+}
+
+struct Simplifier {
+    positions : Vec<glm::DVec3>,      // Per point, vertices sharing a position are one point
+    quadrics  : Vec<Quadric>,
+    versions  : Vec<u32>,
+    alive     : Vec<bool>,
+    point_tris: Vec<Vec<usize>>,      // The triangles around each point, may contain dead ones
+    tris      : Vec<[usize; 3]>,      // Points of each triangle
+    tri_alive : Vec<bool>,
+    live_tris : usize,
+    heap      : BinaryHeap<Reverse<Collapse>>,
+}
+
+impl Simplifier {
+    fn normal(&self, t: &[usize; 3], moved: usize, to: &glm::DVec3) -> glm::DVec3 {
+        let p = |i: usize| if t[i] == moved { *to } else { self.positions[t[i]] };
+        glm::cross(&(p(1) - p(0)), &(p(2) - p(0)))
+    }
+
+    fn queue(&mut self, a: usize, b: usize) {
+        let mut q = self.quadrics[a];
+        q.add(&self.quadrics[b]);
+        let (cost_ab, cost_ba) = (q.error(&self.positions[b]), q.error(&self.positions[a]));
+        let (from, to, cost) = if cost_ab <= cost_ba { (a, b, cost_ab) } else { (b, a, cost_ba) };
+        let versions = (self.versions[from], self.versions[to]);
+        self.heap.push(Reverse(Collapse { cost, from, to, versions }));
+    }
+
+    // Moving `from` onto `to` must not flip or squash any of the triangles that survive it
+    fn can_collapse(&self, from: usize, to: usize) -> bool {
+        let target = self.positions[to];
+        self.point_tris[from].iter()
+            .filter(|&&t| self.tri_alive[t] && !self.tris[t].contains(&to))
+            .all(|&t| {
+                let before = self.normal(&self.tris[t], from, &self.positions[from]);
+                let after = self.normal(&self.tris[t], from, &target);
+                glm::dot(&before, &after) > 0.0 && glm::length(&after) > 1e-12
+            })
+    }
+
+    fn collapse(&mut self, from: usize, to: usize) {
+        let around = std::mem::take(&mut self.point_tris[from]);
+        for t in around {
+            if !self.tri_alive[t] {
+                continue;
+            }
+            if self.tris[t].contains(&to) {
+                self.tri_alive[t] = false;
+                self.live_tris -= 1;
+            } else {
+                for p in self.tris[t].iter_mut() {
+                    if *p == from { *p = to; }
+                }
+                self.point_tris[to].push(t);
+            }
+        }
+        let q = self.quadrics[from];
+        self.quadrics[to].add(&q);
+        self.alive[from] = false;
+        self.versions[to] += 1;
+
+        // Requeue the edges around the survivor, since its quadric changed
+        let tri_alive = &self.tri_alive;
+        self.point_tris[to].retain(|&t| tri_alive[t]);
+        let neighbours: HashSet<usize> = self.point_tris[to].iter()
+            .flat_map(|&t| self.tris[t].iter().cloned())
+            .filter(|&p| p != to)
+            .collect();
+        for p in neighbours {
+            self.queue(to, p);
+        }
+    }
+}
+
+// Reduces the mesh to about `target_ratio` of its triangles. Submesh ranges are kept up to date,
+// but the tangents, if any, are copied from the surviving vertices and should be recomputed.
+pub fn simplify(mesh: &Mesh, target_ratio: f32) -> Mesh {
+    // Vertices sharing a position are collapsed together, so seams don't tear open
+    let mut point_ids: HashMap<[u32; 3], usize> = HashMap::new();
+    let mut positions = vec![];
+    let mut representative = vec![];
+    let vertex_points: Vec<usize> = mesh.vertices.chunks(3).enumerate()
+        .map(|(v, p)| {
+            *point_ids.entry([p[0].to_bits(), p[1].to_bits(), p[2].to_bits()]).or_insert_with(|| {
+                positions.push(glm::vec3(p[0] as f64, p[1] as f64, p[2] as f64));
+                representative.push(v as u32);
+                positions.len() - 1
+            })
+        })
+        .collect();
+
+    let num_points = positions.len();
+    let tris: Vec<[usize; 3]> = mesh.indices.chunks(3)
+        .map(|t| [vertex_points[t[0] as usize], vertex_points[t[1] as usize], vertex_points[t[2] as usize]])
+        .collect();
+
+    let mut s = Simplifier {
+        positions,
+        quadrics: vec![Quadric::default(); num_points],
+        versions: vec![0; num_points],
+        alive: vec![true; num_points],
+        point_tris: vec![vec![]; num_points],
+        tri_alive: vec![true; tris.len()],
+        live_tris: tris.len(),
+        tris,
+        heap: BinaryHeap::new(),
+    };
+
+    // The quadric of each point is the sum of the planes of the triangles around it, weighted by
+    // area. Edges used by only one triangle also get a plane standing up along them.
+    let mut edge_uses: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+    for (t, tri) in s.tris.iter().enumerate() {
+        let (p0, p1, p2) = (s.positions[tri[0]], s.positions[tri[1]], s.positions[tri[2]]);
+        let cross = glm::cross(&(p1 - p0), &(p2 - p0));
+        let area = glm::length(&cross) / 2.0;
+        if area > 0.0 {
+            let n = cross / (2.0 * area);
+            let q = Quadric::plane(n, -glm::dot(&n, &p0), area);
+            for &p in tri {
+                s.quadrics[p].add(&q);
+            }
+        }
+        for i in 0..3 {
+            let (a, b) = (tri[i], tri[(i + 1) % 3]);
+            s.point_tris[a].push(t);
+            edge_uses.entry((a.min(b), a.max(b))).or_insert((t, 0)).1 += 1;
+        }
+    }
+    for (&(a, b), &(t, uses)) in &edge_uses {
+        if uses == 1 && a != b {
+            let tri = s.tris[t];
+            let (pa, pb) = (s.positions[a], s.positions[b]);
+            let face = glm::cross(&(s.positions[tri[1]] - s.positions[tri[0]]), &(s.positions[tri[2]] - s.positions[tri[0]]));
+            let side = glm::cross(&(pb - pa), &face);
+            if glm::length(&side) > 0.0 {
+                let n = glm::normalize(&side);
+                let q = Quadric::plane(n, -glm::dot(&n, &pa), BOUNDARY_WEIGHT * glm::distance2(&pa, &pb));
+                s.quadrics[a].add(&q);
+                s.quadrics[b].add(&q);
+            }
+        }
+    }
+    for &(a, b) in edge_uses.keys() {
+        if a != b {
+            s.queue(a, b);
+        }
+    }
+
+    let target = ((s.tris.len() as f32 * target_ratio.clamp(0.0, 1.0)) as usize).max(1);
+    while s.live_tris > target {
+        let Reverse(c) = match s.heap.pop() {
+            Some(c) => c,
+            None => break,
+        };
+        if !s.alive[c.from] || !s.alive[c.to] || (s.versions[c.from], s.versions[c.to]) != c.versions {
+            continue; // Outdated, a fresh copy has been queued if the edge still exists
+        }
+        if s.can_collapse(c.from, c.to) {
+            s.collapse(c.from, c.to);
+        }
+    }
+
+    // Rebuild the index buffer, keeping the triangles in their original order so the submesh
+    // ranges can simply be recounted. Corners that moved use the survivor's first vertex.
+    let corner = |t: usize, i: usize| {
+        let original = mesh.indices[t * 3 + i];
+        if vertex_points[original as usize] == s.tris[t][i] { original } else { representative[s.tris[t][i]] }
+    };
+    let mut indices = Vec::with_capacity(s.live_tris * 3);
+    let mut result = mesh.clone();
+    let mut tri_start = 0;
+    for submesh in result.submeshes.iter_mut() {
+        let tri_end = tri_start + submesh.index_count as usize / 3;
+        submesh.first_index = indices.len();
+        for t in tri_start..tri_end {
+            if s.tri_alive[t] {
+                indices.extend((0..3).map(|i| corner(t, i)));
+            }
+        }
+        submesh.index_count = (indices.len() - submesh.first_index) as i32;
+        tri_start = tri_end;
+    }
+    for t in tri_start..s.tris.len() {
+        if s.tri_alive[t] {
+            indices.extend((0..3).map(|i| corner(t, i)));
+        }
+    }
+
+    // Drop the vertices nothing refers to anymore
+    let mut new_index = vec![u32::MAX; mesh.vertices.len() / 3];
+    let mut sources = vec![];
+    for &i in &indices {
+        if new_index[i as usize] == u32::MAX {
+            new_index[i as usize] = sources.len() as u32;
+            sources.push(i);
+        }
+    }
+    result.indices = indices.iter().map(|&i| new_index[i as usize]).collect();
+    result.index_count = result.indices.len() as i32;
+    result.remap_vertices(&sources);
+    result.update_bounds();
+    result
+}
+
+// Simplifies the mesh once for every ratio, each level starting from the previous one to save
+// time. The ratios are relative to the original mesh and should be decreasing.
+pub fn lod_chain(mesh: &Mesh, ratios: &[f32]) -> Vec<Mesh> {
+    let original = mesh.indices.len().max(1) as f32;
+    let mut chain: Vec<Mesh> = vec![];
+    for &ratio in ratios {
+        let previous = chain.last().unwrap_or(mesh);
+        let relative = ratio * original / previous.indices.len().max(1) as f32;
+        let lod = simplify(previous, relative);
+        println!("LOD {}: {} triangles", chain.len() + 1, lod.indices.len() / 3);
+        chain.push(lod);
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives;
+
+    fn triangle_count(mesh: &Mesh) -> usize {
+        mesh.indices.len() / 3
+    }
+
+    #[test]
+    fn lod_chain_triangle_counts() {
+        let sphere = primitives::uv_sphere(1.0, 32, 16, [1.0, 1.0, 1.0, 1.0]);
+        let original = triangle_count(&sphere);
+        let ratios = [0.5, 0.25, 0.1];
+        let chain = lod_chain(&sphere, &ratios);
+
+        assert_eq!(chain.len(), ratios.len());
+        let mut previous = original;
+        for (lod, &ratio) in chain.iter().zip(&ratios) {
+            let count = triangle_count(lod);
+            let target = (original as f32 * ratio) as usize;
+            // A collapse removes the two triangles along the edge, so it can overshoot by one
+            assert!(count <= target && count + 2 >= target, "{} triangles, wanted {}", count, target);
+            assert!(count < previous);
+            assert_eq!(lod.index_count as usize, lod.indices.len());
+            assert!(lod.indices.iter().all(|&i| (i as usize) < lod.vertices.len() / 3));
+            previous = count;
+        }
+    }
+
+    #[test]
+    fn simplify_keeps_the_outline() {
+        let plane = primitives::plane(4.0, 4.0, 8, 8, [1.0, 1.0, 1.0, 1.0]);
+        let simplified = simplify(&plane, 0.1);
+        assert!(triangle_count(&simplified) <= triangle_count(&plane) / 10);
+        assert_eq!((simplified.bounds.min, simplified.bounds.max), (plane.bounds.min, plane.bounds.max));
+    }
+
+    #[test]
+    fn full_ratio_keeps_everything() {
+        let cube = primitives::cube(1.0, [1.0, 1.0, 1.0, 1.0]);
+        let same = simplify(&cube, 1.0);
+        assert_eq!(same.indices.len(), cube.indices.len());
+    }
+}
@@ -0,0 +1,68 @@
+use std::os::raw::c_void;
+
+// A 2D texture on the GPU. Deletes the texture when dropped, so like GpuMesh it must be kept for
+// as long as anything is drawn with its id, and dropped on the thread with the OpenGL context.
+#[allow(dead_code)]
+pub struct Texture {
+    pub texture_id: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Texture {
+    // Loads a PNG or JPEG (or anything else the image crate understands) into a 2D texture with
+    // mipmaps. The image is flipped, since OpenGL expects the first row to be the bottom one.
+    pub unsafe fn load(path: &str) -> Result<Texture, image::ImageError> {
+        let image = image::open(path)?.flipv().into_rgba8();
+        let (width, height) = image.dimensions();
+        Ok(Texture::from_rgba(width, height, image.as_raw()))
+    }
+
+    // Like load, but without the flip, for glTF models whose texture coordinates start at the top
+    pub unsafe fn load_unflipped(path: &str) -> Result<Texture, image::ImageError> {
+        let image = image::open(path)?.into_rgba8();
+        let (width, height) = image.dimensions();
+        Ok(Texture::from_rgba(width, height, image.as_raw()))
+    }
+
+    // Uploads 8 bit RGBA pixels, row by row, into a 2D texture with mipmaps. The first row ends up
+    // at texture coordinate 0, which is the bottom for OBJ files but the top for glTF.
+    pub unsafe fn from_rgba(width: u32, height: u32, pixels: &[u8]) -> Texture {
+        let mut texture_id: gl::types::GLuint = 0;
+        gl::GenTextures(1, &mut texture_id);
+        gl::BindTexture(gl::TEXTURE_2D, texture_id);
+        gl::TexImage2D(
+            gl::TEXTURE_2D,
+            0,
+            gl::RGBA8 as i32,
+            width as i32,
+            height as i32,
+            0,
+            gl::RGBA,
+            gl::UNSIGNED_BYTE,
+            pixels.as_ptr() as *const c_void,
+        );
+        gl::GenerateMipmap(gl::TEXTURE_2D);
+
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR_MIPMAP_LINEAR as i32);
+        gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+
+        Texture { texture_id, width, height }
+    }
+
+    #[allow(dead_code)]
+    pub unsafe fn bind(&self, unit: u32) {
+        gl::ActiveTexture(gl::TEXTURE0 + unit);
+        gl::BindTexture(gl::TEXTURE_2D, self.texture_id);
+    }
+}
+
+impl Drop for Texture {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteTextures(1, &self.texture_id);
+        }
+    }
+}
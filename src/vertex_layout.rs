@@ -0,0 +1,181 @@
+use std::ptr;
+
+use crate::mesh::Mesh;
+
+// Which of the arrays of a Mesh an attribute is read from
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Attribute {
+    Position,
+    Color,
+    Uv,
+    Tangent,
+    Bitangent,
+    Normal,
+}
+
+impl Attribute {
+    // The values of this attribute for one vertex
+    fn values<'a>(&self, mesh: &'a Mesh, vertex: usize) -> &'a [f32] {
+        let (data, n) = self.source(mesh);
+        &data[vertex * n..(vertex + 1) * n]
+    }
+
+    fn source<'a>(&self, mesh: &'a Mesh) -> (&'a [f32], usize) {
+        match self {
+            Attribute::Position  => (&mesh.vertices, 3),
+            Attribute::Color     => (&mesh.colors, 4),
+            Attribute::Uv        => (&mesh.uvs, 2),
+            Attribute::Tangent   => (&mesh.tangents, 4),
+            Attribute::Bitangent => (&mesh.bitangents, 3),
+            Attribute::Normal    => (&mesh.normals, 3),
+        }
+    }
+}
+
+// How the values of an attribute are stored in the buffer
+#[allow(dead_code)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ComponentType {
+    Float,
+    Byte,
+    UnsignedByte,
+    Short,
+    UnsignedShort,
+}
+
+impl ComponentType {
+    fn size(&self) -> usize {
+        match self {
+            ComponentType::Float => 4,
+            ComponentType::Byte | ComponentType::UnsignedByte => 1,
+            ComponentType::Short | ComponentType::UnsignedShort => 2,
+        }
+    }
+
+    fn gl_type(&self) -> gl::types::GLenum {
+        match self {
+            ComponentType::Float         => gl::FLOAT,
+            ComponentType::Byte          => gl::BYTE,
+            ComponentType::UnsignedByte  => gl::UNSIGNED_BYTE,
+            ComponentType::Short         => gl::SHORT,
+            ComponentType::UnsignedShort => gl::UNSIGNED_SHORT,
+        }
+    }
+
+    // Normalized integers cover [0, 1] if unsigned and [-1, 1] if signed, like OpenGL expects
+    fn write(&self, buf: &mut Vec<u8>, value: f32, normalized: bool) {
+        let scaled = |max: f32, min: f32| if normalized { (value.max(min) * max).min(max).round() } else { value };
+        match self {
+            ComponentType::Float         => buf.extend_from_slice(&value.to_le_bytes()),
+            ComponentType::Byte          => buf.push(scaled(127.0, -1.0) as i8 as u8),
+            ComponentType::UnsignedByte  => buf.push(scaled(255.0, 0.0) as u8),
+            ComponentType::Short         => buf.extend_from_slice(&(scaled(32767.0, -1.0) as i16).to_le_bytes()),
+            ComponentType::UnsignedShort => buf.extend_from_slice(&(scaled(65535.0, 0.0) as u16).to_le_bytes()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VertexAttribute {
+    pub attribute      : Attribute,
+    pub location       : u32,
+    pub component_type : ComponentType,
+    pub count          : usize,
+    pub normalized     : bool,
+}
+
+impl VertexAttribute {
+    // Attributes start on a 4 byte boundary, as some drivers are slow with anything else
+    fn size(&self) -> usize {
+        (self.component_type.size() * self.count).div_ceil(4) * 4
+    }
+}
+
+// The layout of one vertex in an interleaved vertex buffer, listing the attributes in the order
+// they're stored
+#[derive(Clone, Debug)]
+pub struct VertexLayout {
+    pub attributes: Vec<VertexAttribute>,
+}
+
+impl VertexLayout {
+    pub fn new() -> VertexLayout {
+        VertexLayout { attributes: vec![] }
+    }
+
+    pub fn attribute(mut self, attribute: Attribute, location: u32, component_type: ComponentType, count: usize, normalized: bool) -> VertexLayout {
+        self.attributes.push(VertexAttribute { attribute, location, component_type, count, normalized });
+        self
+    }
+
+    // The layout simple.vert expects
+    pub fn standard() -> VertexLayout {
+        VertexLayout::new()
+            .attribute(Attribute::Position,  0, ComponentType::Float,        3, false)
+            .attribute(Attribute::Color,     1, ComponentType::UnsignedByte, 4, true)
+            .attribute(Attribute::Uv,        2, ComponentType::Float,        2, false)
+            .attribute(Attribute::Tangent,   3, ComponentType::Float,        4, false)
+            .attribute(Attribute::Bitangent, 4, ComponentType::Float,        3, false)
+            .attribute(Attribute::Normal,    5, ComponentType::Float,        3, false)
+    }
+
+    // The size of one vertex in bytes
+    pub fn stride(&self) -> usize {
+        self.attributes.iter().map(VertexAttribute::size).sum()
+    }
+
+    // The same layout without the attributes the mesh has no data for, e.g. UVs on an untextured
+    // mesh. The shader gets the default attribute value for those instead.
+    pub fn for_mesh(&self, mesh: &Mesh) -> VertexLayout {
+        VertexLayout {
+            attributes: self.attributes.iter()
+                .filter(|a| !a.attribute.source(mesh).0.is_empty())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    // Interleaves the attributes of every vertex into one buffer. Components beyond what the mesh
+    // has are filled with zeros, and extra components are left out.
+    pub fn pack(&self, mesh: &Mesh) -> Vec<u8> {
+        let num_verts = mesh.vertices.len() / 3;
+        let mut buf = Vec::with_capacity(num_verts * self.stride());
+        for v in 0..num_verts {
+            for a in &self.attributes {
+                let start = buf.len();
+                let values = a.attribute.values(mesh, v);
+                for c in 0..a.count {
+                    a.component_type.write(&mut buf, values.get(c).cloned().unwrap_or(0.0), a.normalized);
+                }
+                buf.resize(start + a.size(), 0);
+            }
+        }
+        buf
+    }
+
+    // Points the attributes of the currently bound VAO into the currently bound array buffer
+    pub unsafe fn enable(&self) {
+        let stride = self.stride() as i32;
+        let mut offset = 0;
+        for a in &self.attributes {
+            gl::VertexAttribPointer(
+                a.location,
+                a.count as i32,
+                a.component_type.gl_type(),
+                if a.normalized { gl::TRUE } else { gl::FALSE },
+                stride,
+                ptr::null::<u8>().add(offset) as *const _,
+            );
+            gl::EnableVertexAttribArray(a.location);
+            offset += a.size();
+        }
+    }
+
+    // Undoes enable(), for when the VAO is about to get a different layout
+    pub unsafe fn disable(&self) {
+        for a in &self.attributes {
+            gl::DisableVertexAttribArray(a.location);
+        }
+    }
+}